@@ -0,0 +1,95 @@
+use crate::chain_monitor::*;
+
+use lightning::ln::{router, channelmanager, msgs};
+use lightning::ln::msgs::RoutingMessageHandler;
+
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often we check whether we have a public channel yet, until we first announce.
+const ANNOUNCE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Nodes may forget announcements they haven't seen refreshed in a while, so we re-broadcast
+/// ours this often.
+const REANNOUNCE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What we tell the network about ourselves in our node_announcement.
+#[derive(Clone)]
+pub struct AnnouncementSettings {
+	/// At most 32 bytes of UTF-8.
+	pub alias: String,
+	pub color: [u8; 3],
+	/// At most one of each type of address, in BOLT 7 order (see sort_addresses).
+	pub addresses: Vec<msgs::NetAddress>,
+	/// Whether to announce automatically once we have a public channel, and then periodically.
+	pub auto: bool,
+}
+
+fn address_type(addr: &msgs::NetAddress) -> u8 {
+	match addr {
+		msgs::NetAddress::IPv4 { .. } => 1,
+		msgs::NetAddress::IPv6 { .. } => 2,
+		msgs::NetAddress::OnionV2 { .. } => 3,
+		msgs::NetAddress::OnionV3 { .. } => 4,
+	}
+}
+
+/// Sorts addresses the way node_announcements must list them, failing if there's more than one
+/// of any type, which peers would reject.
+pub fn sort_addresses(addresses: &mut Vec<msgs::NetAddress>) -> Result<(), String> {
+	addresses.sort_by_key(address_type);
+	if addresses.windows(2).any(|pair| address_type(&pair[0]) == address_type(&pair[1])) {
+		return Err("node announcements can only include one address of each type (IPv4, IPv6, Tor v2 and Tor v3)".to_string());
+	}
+	Ok(())
+}
+
+/// Parses an RGB color like #3399ff.
+pub fn parse_color(s: &str) -> Option<[u8; 3]> {
+	let hex = s.trim_start_matches('#');
+	if hex.len() != 6 { return None; }
+	let mut color = [0; 3];
+	for (i, byte) in color.iter_mut().enumerate() {
+		*byte = u8::from_str_radix(hex.get(i*2..i*2 + 2)?, 16).ok()?;
+	}
+	Some(color)
+}
+
+pub fn broadcast_announcement(channel_manager: &channelmanager::SimpleArcChannelManager<crate::ChannelMonitor, ChainInterface, FeeEstimator>, settings: &AnnouncementSettings) {
+	let mut alias = [0u8; 32];
+	alias[..settings.alias.len()].copy_from_slice(settings.alias.as_bytes());
+	channel_manager.broadcast_node_announcement(settings.color, alias, settings.addresses.clone());
+}
+
+/// Nodes ignore announcements from nodes without any public channels, so there's no point
+/// announcing until one of our channels is in the network graph.
+fn have_public_channel(channel_manager: &channelmanager::SimpleArcChannelManager<crate::ChannelMonitor, ChainInterface, FeeEstimator>, router: &router::Router) -> bool {
+	channel_manager.list_channels().iter().filter_map(|chan| chan.short_channel_id).any(|short_channel_id| {
+		router.get_next_channel_announcements(short_channel_id, 1).first()
+			.map(|(announcement, _, _)| announcement.contents.short_channel_id == short_channel_id)
+			.unwrap_or(false)
+	})
+}
+
+/// Announces our node as soon as we have a public channel, then again every REANNOUNCE_INTERVAL.
+pub fn spawn_announcer(channel_manager: channelmanager::SimpleArcChannelManager<crate::ChannelMonitor, ChainInterface, FeeEstimator>,
+	router: Arc<router::Router>, settings: AnnouncementSettings, event_notify: mpsc::Sender<()>) -> tokio::task::JoinHandle<()>
+{
+	tokio::spawn(async move {
+		let mut last_announced: Option<Instant> = None;
+		let mut intvl = tokio::time::interval(ANNOUNCE_CHECK_INTERVAL);
+		loop {
+			intvl.tick().await;
+			if let Some(time) = last_announced {
+				if time.elapsed() < REANNOUNCE_INTERVAL { continue; }
+			}
+			if !have_public_channel(&channel_manager, &router) { continue; }
+			println!("Broadcasting our node announcement");
+			broadcast_announcement(&channel_manager, &settings);
+			let _ = event_notify.clone().try_send(());
+			last_announced = Some(Instant::now());
+		}
+	})
+}
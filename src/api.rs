@@ -0,0 +1,273 @@
+use crate::commands::*;
+use crate::events::*;
+use crate::net::*;
+use crate::persist::*;
+use crate::utils::*;
+
+use futures_util::stream::TryStreamExt;
+
+use hyper::{Body, Request, Response, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+
+use lightning::chain::keysinterface::KeysInterface;
+
+use bitcoin::secp256k1::key::PublicKey;
+
+use rand::{thread_rng, Rng};
+
+use tokio::sync::broadcast::RecvError;
+use tokio::task::JoinHandle;
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Arc;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The node refused or failed to do what was asked, the message says why.
+const NODE_ERROR: i64 = -32000;
+
+/// Returns the user:password clients must authenticate to the API with, writing a fresh random
+/// password to api_cookie in the storage directory if none is configured (like bitcoind's
+/// .cookie file).
+pub fn api_credentials(configured: &Option<String>, storage_path: &str) -> Result<String, String> {
+	if let Some(auth) = configured {
+		return Ok(auth.clone());
+	}
+	let dir = storage_directory(storage_path).ok_or("In-memory storage has no directory for api_cookie, set api.auth")?;
+	let mut password = [0; 32];
+	thread_rng().fill_bytes(&mut password);
+	let auth = "__cookie__:".to_string() + &hex_str(&password);
+	let path = format!("{}/api_cookie", dir);
+	let _ = fs::remove_file(&path);
+	fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)
+		.and_then(|mut f| f.write_all(auth.as_bytes()))
+		.map_err(|e| format!("Failed to write {}: {}", path, e))?;
+	Ok(auth)
+}
+
+fn auth_matches(header: &[u8], expected: &[u8]) -> bool {
+	if header.len() != expected.len() { return false; }
+	header.iter().zip(expected.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn rpc_err<T>(code: i64, message: String) -> Result<T, (i64, String)> {
+	Err((code, message))
+}
+
+fn param<'a>(params: &'a serde_json::Value, name: &str) -> Result<&'a serde_json::Value, (i64, String)> {
+	match params.get(name) {
+		Some(v) if !v.is_null() => Ok(v),
+		_ => rpc_err(INVALID_PARAMS, format!("Missing parameter {}", name)),
+	}
+}
+
+fn param_str<'a>(params: &'a serde_json::Value, name: &str) -> Result<&'a str, (i64, String)> {
+	param(params, name)?.as_str().ok_or((INVALID_PARAMS, format!("{} must be a string", name)))
+}
+
+fn param_u64(params: &serde_json::Value, name: &str) -> Result<u64, (i64, String)> {
+	param(params, name)?.as_u64().ok_or((INVALID_PARAMS, format!("{} must be a non-negative integer", name)))
+}
+
+fn param_pubkey(params: &serde_json::Value, name: &str) -> Result<PublicKey, (i64, String)> {
+	let s = param_str(params, name)?;
+	match hex_to_compressed_pubkey(s) {
+		Some(pk) => Ok(pk),
+		None => rpc_err(INVALID_PARAMS, format!("{} must be a hex compressed public key", name)),
+	}
+}
+
+fn param_channel_id(params: &serde_json::Value, name: &str) -> Result<[u8; 32], (i64, String)> {
+	match hex_to_vec(param_str(params, name)?) {
+		Some(ref id) if id.len() == 32 => {
+			let mut channel_id = [0; 32];
+			channel_id.copy_from_slice(id);
+			Ok(channel_id)
+		},
+		_ => rpc_err(INVALID_PARAMS, format!("{} must be a 32-byte hex channel id", name)),
+	}
+}
+
+async fn call_method(handler: &CommandHandler, method: &str, params: &serde_json::Value) -> Result<serde_json::Value, (i64, String)> {
+	match method {
+		"getinfo" => {
+			let channels = handler.channel_manager.list_channels();
+			Ok(serde_json::json!({
+				"node_id": hex_str(&PublicKey::from_secret_key(&handler.secp_ctx, &handler.keys.get_node_secret()).serialize()),
+				"network": handler.network.to_string(),
+				"num_peers": handler.peer_manager.get_peer_node_ids().len(),
+				"num_channels": channels.len(),
+				"num_usable_channels": channels.iter().filter(|chan| chan.is_live).count(),
+			}))
+		},
+		"connect" => {
+			let node_id = param_pubkey(params, "node_id")?;
+			let addr: PeerAddress = param_str(params, "address")?.parse().map_err(|e| (INVALID_PARAMS, e))?;
+			handler.connect(node_id, addr).await.map_err(|e| (NODE_ERROR, e))?;
+			Ok(serde_json::Value::Null)
+		},
+		"openchannel" => {
+			let node_id = param_pubkey(params, "node_id")?;
+			let value_sat = param_u64(params, "value_sat")?;
+			let push_msat = if params.get("push_msat").map(|v| v.is_null()).unwrap_or(true) { 0 } else { param_u64(params, "push_msat")? };
+			handler.open_channel(node_id, value_sat, push_msat).map_err(|e| (NODE_ERROR, e))?;
+			Ok(serde_json::Value::Null)
+		},
+		"closechannel" => {
+			let channel_id = param_channel_id(params, "channel_id")?;
+			let force = match params.get("force") {
+				None | Some(serde_json::Value::Null) => false,
+				Some(v) => v.as_bool().ok_or((INVALID_PARAMS, "force must be true or false".to_string()))?,
+			};
+			if force {
+				handler.channel_manager.force_close_channel(&channel_id);
+			} else {
+				handler.close_channel(&channel_id).map_err(|e| (NODE_ERROR, e))?;
+			}
+			Ok(serde_json::Value::Null)
+		},
+		"listpeers" => {
+			let peers: Vec<serde_json::Value> = handler.peer_manager.get_peer_node_ids().iter()
+				.map(|node_id| serde_json::json!({ "node_id": hex_str(&node_id.serialize()) }))
+				.collect();
+			Ok(serde_json::Value::Array(peers))
+		},
+		"listchannels" => {
+			let channels: Vec<serde_json::Value> = handler.channel_manager.list_channels().iter()
+				.map(|chan| serde_json::json!({
+					"channel_id": hex_str(&chan.channel_id[..]),
+					"short_channel_id": chan.short_channel_id,
+					"node_id": hex_str(&chan.remote_network_id.serialize()),
+					"value_sat": chan.channel_value_satoshis,
+					"outbound_capacity_msat": chan.outbound_capacity_msat,
+					"inbound_capacity_msat": chan.inbound_capacity_msat,
+					"is_live": chan.is_live,
+				}))
+				.collect();
+			Ok(serde_json::Value::Array(channels))
+		},
+		"payinvoice" => {
+			let invoice = param_str(params, "invoice")?;
+			let amt_arg = if params.get("amount_msat").map(|v| v.is_null()).unwrap_or(true) { None } else { Some(param_u64(params, "amount_msat")?.to_string()) };
+			let (payment_hash, amt, parts, incomplete) = handler.pay_invoice(invoice, amt_arg.as_ref().map(|s| &s[..])).map_err(|e| (NODE_ERROR, e))?;
+			Ok(serde_json::json!({
+				"payment_hash": hex_str(&payment_hash.0),
+				"amount_msat": amt,
+				"parts": parts,
+				"incomplete": incomplete,
+			}))
+		},
+		"createinvoice" => {
+			let (payment_hash, invoice) = handler.create_invoice(param_u64(params, "amount_msat")?).map_err(|e| (NODE_ERROR, e))?;
+			Ok(serde_json::json!({
+				"payment_hash": hex_str(&payment_hash.0),
+				"invoice": invoice,
+			}))
+		},
+		"setfees" => {
+			let fee = param_u64(params, "proportional_millionths")?;
+			let fee = u32::try_from(fee).map_err(|_| (INVALID_PARAMS, format!("proportional_millionths must be at most {}", std::u32::MAX)))?;
+			handler.set_fees(fee).map_err(|e| (NODE_ERROR, e))?;
+			Ok(serde_json::Value::Null)
+		},
+		_ => rpc_err(METHOD_NOT_FOUND, format!("Unknown method {}", method)),
+	}
+}
+
+fn rpc_response(id: serde_json::Value, res: Result<serde_json::Value, (i64, String)>) -> Response<Body> {
+	let body = match res {
+		Ok(result) => serde_json::json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+		Err((code, message)) => serde_json::json!({ "jsonrpc": "2.0", "error": { "code": code, "message": message }, "id": id }),
+	};
+	Response::builder()
+		.header("Content-Type", "application/json")
+		.body(Body::from(serde_json::to_vec(&body).unwrap())).unwrap()
+}
+
+/// Streams every record published to events as a server-sent event until the client goes away.
+fn event_stream_response(events: &EventStream) -> Response<Body> {
+	let stream = futures_util::stream::unfold(events.subscribe(), |mut receiver| async move {
+		let record = match receiver.recv().await {
+			Ok(record) => record,
+			Err(RecvError::Lagged(missed)) => serde_json::json!({ "type": "lagged", "missed": missed }).to_string(),
+			Err(RecvError::Closed) => return None,
+		};
+		Some((Ok::<_, std::io::Error>(format!("data: {}\n\n", record)), receiver))
+	});
+	Response::builder()
+		.header("Content-Type", "text/event-stream")
+		.header("Cache-Control", "no-cache")
+		.body(Body::wrap_stream(stream)).unwrap()
+}
+
+async fn handle_request(req: Request<Body>, handler: Arc<CommandHandler>, events: Arc<EventStream>, expected_auth: Arc<String>) -> Result<Response<Body>, hyper::Error> {
+	let authorized = match req.headers().get("Authorization") {
+		Some(header) => auth_matches(header.as_bytes(), expected_auth.as_bytes()),
+		None => false,
+	};
+	if !authorized {
+		return Ok(Response::builder().status(StatusCode::UNAUTHORIZED)
+			.header("WWW-Authenticate", "Basic realm=\"node\"")
+			.body(Body::empty()).unwrap());
+	}
+	if req.method() == hyper::Method::GET && req.uri().path() == "/events" {
+		return Ok(event_stream_response(&events));
+	}
+	if req.method() != hyper::Method::POST {
+		return Ok(Response::builder().status(StatusCode::METHOD_NOT_ALLOWED).body(Body::empty()).unwrap());
+	}
+
+	let body = req.into_body().map_ok(|b| b.to_vec()).try_concat().await?;
+	let request: serde_json::Value = match serde_json::from_slice(&body) {
+		Ok(v) => v,
+		Err(e) => return Ok(rpc_response(serde_json::Value::Null, rpc_err(PARSE_ERROR, format!("Failed to parse request: {}", e)))),
+	};
+	let id = request.get("id").cloned().unwrap_or(serde_json::Value::Null);
+	let method = match request.get("method").and_then(|m| m.as_str()) {
+		Some(method) => method,
+		None => return Ok(rpc_response(id, rpc_err(INVALID_REQUEST, "Request must have a method".to_string()))),
+	};
+	let params = match request.get("params") {
+		None | Some(serde_json::Value::Null) => serde_json::json!({}),
+		Some(params) if params.is_object() => params.clone(),
+		Some(_) => return Ok(rpc_response(id, rpc_err(INVALID_PARAMS, "params must be an object of named parameters".to_string()))),
+	};
+	Ok(rpc_response(id, call_method(&handler, method, &params).await))
+}
+
+/// Serves a JSON-RPC 2.0 API over HTTP on addr, authenticated with HTTP basic auth using auth (as
+/// user:password). Methods take named params:
+///  * getinfo
+///  * connect { node_id, address }
+///  * openchannel { node_id, value_sat, push_msat (optional) }
+///  * closechannel { channel_id, force (optional) }
+///  * listpeers
+///  * listchannels
+///  * payinvoice { invoice, amount_msat (only if the invoice has no amount) }
+///  * createinvoice { amount_msat }
+///  * setfees { proportional_millionths } (for channels opened after the next restart)
+///
+/// GET /events streams every record published to events as server-sent events.
+pub fn spawn_api_server(addr: SocketAddr, auth: &str, handler: Arc<CommandHandler>, events: Arc<EventStream>) -> Result<JoinHandle<()>, String> {
+	let expected_auth = Arc::new("Basic ".to_string() + &base64::encode(auth));
+	let server = hyper::Server::try_bind(&addr).map_err(|e| format!("Failed to bind API server to {}: {}", addr, e))?
+		.serve(make_service_fn(move |_| {
+			let handler = handler.clone();
+			let events = events.clone();
+			let expected_auth = expected_auth.clone();
+			async move {
+				Ok::<_, hyper::Error>(service_fn(move |req| handle_request(req, handler.clone(), events.clone(), expected_auth.clone())))
+			}
+		}));
+	Ok(tokio::spawn(async move {
+		if let Err(e) = server.await {
+			println!("API server failed: {}", e);
+		}
+	}))
+}
@@ -0,0 +1,175 @@
+use crate::persist::*;
+use crate::rpc_client::*;
+use crate::utils::*;
+
+use lightning::chain::transaction::OutPoint;
+
+use bitcoin::hashes::hex::{ToHex, FromHex};
+use bitcoin::hash_types::Txid;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// How many blocks after a closed channel's funding output is spent we wait before considering
+/// archiving its monitor. This comfortably exceeds any to_self_delay we accept plus HTLC timeouts,
+/// so all of our claims should have long since confirmed.
+const ARCHIVE_SAFETY_DEPTH: u64 = 4032;
+/// A commitment transaction has at most two balance outputs and 483 HTLCs in each direction,
+/// which bounds how many outputs we check when bitcoind can't give us the transaction itself.
+const MAX_COMMITMENT_OUTPUTS: usize = 2 + 2 * 483;
+
+fn outpoint_str(funding_txo: &OutPoint) -> String {
+	format!("{}:{}", funding_txo.txid.to_hex(), funding_txo.index)
+}
+
+fn outpoint_from_str(s: &str) -> Option<OutPoint> {
+	let mut parts = s.splitn(2, ':');
+	let txid = Txid::from_hex(parts.next()?).ok()?;
+	Some(OutPoint { txid, index: parts.next()?.parse().ok()? })
+}
+
+/// Moves the monitors of channels which have been closed and fully resolved on-chain out of the
+/// set we load (and sync to the chain tip) on every startup.
+///
+/// A monitor is archived once its channel is closed, its funding output has been spent for
+/// ARCHIVE_SAFETY_DEPTH blocks and none of the outputs it is watching remain unspent.
+pub struct MonitorArchiver {
+	store: Arc<dyn NodeStore>,
+	/// The height at which we first saw each closed channel's funding output spent.
+	spent_heights: Mutex<HashMap<OutPoint, u64>>,
+}
+impl MonitorArchiver {
+	pub fn new(store: Arc<dyn NodeStore>) -> Self {
+		let mut spent_heights = HashMap::new();
+		match store.read(&StorageKey::ArchiveState) {
+			Ok(Some(data)) => match serde_json::from_slice::<serde_json::Value>(&data) {
+				Ok(v) => if let Some(heights) = v["spent_heights"].as_object() {
+					for (outpoint, height) in heights.iter() {
+						if let (Some(funding_txo), Some(height)) = (outpoint_from_str(outpoint), height.as_u64()) {
+							spent_heights.insert(funding_txo, height);
+						}
+					}
+				},
+				Err(e) => println!("WARNING: Failed to parse archive_state, closed channels will be archived later than they could be: {}", e),
+			},
+			Ok(None) => {},
+			Err(e) => println!("WARNING: Failed to read archive_state, closed channels will be archived later than they could be: {}", e),
+		}
+		Self { store, spent_heights: Mutex::new(spent_heights) }
+	}
+
+	fn write_state(&self, spent_heights: &HashMap<OutPoint, u64>) {
+		let mut heights = serde_json::Map::new();
+		for (funding_txo, height) in spent_heights.iter() {
+			heights.insert(outpoint_str(funding_txo), serde_json::json!(height));
+		}
+		let data = serde_json::to_vec(&serde_json::json!({ "spent_heights": heights })).unwrap();
+		if let Err(e) = self.store.write(&StorageKey::ArchiveState, &data) {
+			println!("WARNING: Failed to write archive_state: {}", e);
+		}
+	}
+
+	/// Returns true if any output of txid whose script is in scripts is still unspent (or if we
+	/// couldn't tell).
+	async fn has_unspent_watched_output(rpc_client: &RPCClient, txid: &Txid, scripts: &HashSet<String>) -> bool {
+		let txid_param = "\"".to_string() + &txid.to_hex() + "\"";
+		let output_count = match rpc_client.make_rpc_call("getrawtransaction", &[&txid_param, "true"], true).await {
+			Ok(tx) => match tx["vout"].as_array() {
+				Some(vout) => vout.len(),
+				None => return true,
+			},
+			// Without -txindex bitcoind can't find confirmed transactions, so just try every
+			// output index the transaction could possibly have.
+			Err(()) => MAX_COMMITMENT_OUTPUTS,
+		};
+		for idx in 0..output_count {
+			match rpc_client.make_rpc_call("gettxout", &[&txid_param, &idx.to_string(), "false"], false).await {
+				Ok(txout) => if let Some(script) = txout["scriptPubKey"]["hex"].as_str() {
+					if scripts.contains(script) { return true; }
+				},
+				Err(()) => return true,
+			}
+		}
+		false
+	}
+
+	/// Checks every monitor whose channel isn't in open_channel_ids, archiving any which have been
+	/// fully resolved. The monitor stays loaded until we restart.
+	pub async fn check(&self, rpc_client: &RPCClient, monitor: &crate::ChannelMonitor, open_channel_ids: &HashSet<[u8; 32]>) {
+		let height = match rpc_client.make_rpc_call("getblockcount", &[], false).await {
+			Ok(height) => match height.as_u64() {
+				Some(height) => height,
+				None => return,
+			},
+			Err(()) => return,
+		};
+		let funding_txos = match self.store.list_monitors() {
+			Ok(funding_txos) => funding_txos,
+			Err(e) => {
+				println!("WARNING: Failed to list channel monitors to check for resolved channels: {}", e);
+				return;
+			}
+		};
+		for funding_txo in funding_txos {
+			if open_channel_ids.contains(&funding_txo.to_channel_id()) { continue; }
+
+			let spent_height = self.spent_heights.lock().unwrap().get(&funding_txo).cloned();
+			let spent_height = match spent_height {
+				Some(spent_height) => spent_height,
+				None => {
+					// Only count the funding output as spent once the spend has confirmed.
+					let txid_param = "\"".to_string() + &funding_txo.txid.to_hex() + "\"";
+					match rpc_client.make_rpc_call("gettxout", &[&txid_param, &funding_txo.index.to_string(), "false"], false).await {
+						Ok(txout) if txout.is_null() => {
+							let mut spent_heights = self.spent_heights.lock().unwrap();
+							spent_heights.insert(funding_txo, height);
+							self.write_state(&spent_heights);
+							height
+						},
+						_ => continue,
+					}
+				},
+			};
+			if height < spent_height + ARCHIVE_SAFETY_DEPTH { continue; }
+
+			let watched: Vec<(Txid, HashSet<String>)> = match monitor.monitor.get_monitor_ref_by_key(&funding_txo) {
+				Some(chan_monitor) => chan_monitor.get_outputs_to_watch().iter()
+					.map(|(txid, scripts)| (*txid, scripts.iter().map(|script| script[..].to_hex()).collect()))
+					.collect(),
+				None => continue,
+			};
+			let mut resolved = true;
+			for (txid, scripts) in watched.iter() {
+				if Self::has_unspent_watched_output(rpc_client, txid, scripts).await {
+					resolved = false;
+					break;
+				}
+			}
+			if !resolved { continue; }
+
+			match self.store.archive_monitor(&funding_txo) {
+				Ok(()) => {
+					println!("Archived the channel monitor for closed channel {}, it has been fully resolved on-chain", hex_str(&funding_txo.to_channel_id()));
+					let mut spent_heights = self.spent_heights.lock().unwrap();
+					spent_heights.remove(&funding_txo);
+					self.write_state(&spent_heights);
+				},
+				Err(e) => println!("WARNING: Failed to archive the channel monitor for closed channel {}: {}", hex_str(&funding_txo.to_channel_id()), e),
+			}
+		}
+	}
+}
+
+/// Lists the channels whose monitors have been archived.
+pub fn format_archived_monitors(store: &Arc<dyn NodeStore>) -> String {
+	match store.list_archived_monitors() {
+		Ok(funding_txos) => {
+			let mut res = "Archived channels:\n".to_string();
+			for funding_txo in funding_txos {
+				res += &format!("id: {}, funding outpoint: {}\n", hex_str(&funding_txo.to_channel_id()), outpoint_str(&funding_txo));
+			}
+			res
+		},
+		Err(e) => format!("Failed to list archived channel monitors: {}\n", e),
+	}
+}
@@ -0,0 +1,209 @@
+use crate::net::*;
+use crate::persist::*;
+use crate::reconnect::*;
+use crate::rpc_client::*;
+use crate::utils::*;
+
+use lightning::chain::keysinterface::{ChannelKeys, KeysInterface, KeysManager};
+use lightning::chain::transaction::OutPoint;
+use lightning::ln::chan_utils;
+use lightning::ln::channelmanager::ChannelDetails;
+use lightning::util::logger::Logger;
+
+use bitcoin::secp256k1::key::PublicKey;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::hashes::hex::{ToHex, FromHex};
+use bitcoin::hash_types::Txid;
+use bitcoin::network::constants::Network;
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+/// What we need to get a channel's funds back if we lose all other state: who to ask to
+/// force-close it and how to derive the key their commitment transaction pays us to.
+pub struct BackedUpChannel {
+	pub channel_id: [u8; 32],
+	pub funding_txo: OutPoint,
+	pub peer_node_id: PublicKey,
+	pub peer_addresses: Vec<PeerAddress>,
+	pub channel_value_satoshis: u64,
+	/// The starting time the KeysManager was initialized with when this channel's keys were
+	/// derived, or None if the channel pre-dates the backup and we don't know it.
+	pub keys_start_time: Option<(u64, u32)>,
+}
+
+fn channel_to_json(chan: &BackedUpChannel) -> serde_json::Value {
+	serde_json::json!({
+		"channel_id": hex_str(&chan.channel_id),
+		"funding_txid": chan.funding_txo.txid.to_hex(),
+		"funding_index": chan.funding_txo.index,
+		"peer_node_id": hex_str(&chan.peer_node_id.serialize()),
+		"peer_addresses": chan.peer_addresses.iter().map(|addr| addr.to_string()).collect::<Vec<_>>(),
+		"channel_value_satoshis": chan.channel_value_satoshis,
+		"keys_start_time": chan.keys_start_time.map(|(secs, nanos)| serde_json::json!([secs, nanos])),
+	})
+}
+
+fn channel_from_json(v: &serde_json::Value) -> Option<BackedUpChannel> {
+	let chan_id_vec = hex_to_vec(v["channel_id"].as_str()?)?;
+	if chan_id_vec.len() != 32 { return None; }
+	let mut channel_id = [0; 32];
+	channel_id.copy_from_slice(&chan_id_vec);
+	let funding_txo = OutPoint {
+		txid: Txid::from_hex(v["funding_txid"].as_str()?).ok()?,
+		index: v["funding_index"].as_u64()? as u16,
+	};
+	let mut peer_addresses = Vec::new();
+	for addr in v["peer_addresses"].as_array()? {
+		peer_addresses.push(addr.as_str()?.parse().ok()?);
+	}
+	let keys_start_time = match v["keys_start_time"].as_array() {
+		Some(time) if time.len() == 2 => Some((time[0].as_u64()?, time[1].as_u64()? as u32)),
+		_ => None,
+	};
+	Some(BackedUpChannel {
+		channel_id,
+		funding_txo,
+		peer_node_id: PublicKey::from_slice(&hex_to_vec(v["peer_node_id"].as_str()?)?).ok()?,
+		peer_addresses,
+		channel_value_satoshis: v["channel_value_satoshis"].as_u64()?,
+		keys_start_time,
+	})
+}
+
+pub fn parse_backup(data: &[u8]) -> Result<Vec<BackedUpChannel>, String> {
+	let v: serde_json::Value = serde_json::from_slice(data).map_err(|e| format!("Channel backup is not valid JSON: {}", e))?;
+	if v["version"].as_u64() != Some(1) {
+		return Err("Unknown channel backup version".to_string());
+	}
+	let mut res = Vec::new();
+	for chan in v["channels"].as_array().ok_or("Channel backup has no channel list")? {
+		res.push(channel_from_json(chan).ok_or("Channel backup contained a malformed channel")?);
+	}
+	Ok(res)
+}
+
+/// Maintains a static channel backup, which is rewritten (to the store, as channel_backup)
+/// whenever the set of funded channels changes.
+pub struct ChannelBackup {
+	store: Arc<dyn NodeStore>,
+	node_id: PublicKey,
+	keys_start_time: (u64, u32),
+	/// Key derivation start times for every channel we've seen, carried over between backups.
+	start_times: Mutex<HashMap<[u8; 32], Option<(u64, u32)>>>,
+	address_book: Arc<PeerAddressBook>,
+	/// The channels and their peers' addresses as of the last backup we wrote.
+	last_backed_up: Mutex<Vec<([u8; 32], Vec<PeerAddress>)>>,
+}
+impl ChannelBackup {
+	/// existing_channels should be the channels we loaded on startup, any of which aren't in the
+	/// previous backup have unknown key derivation start times. Peer addresses are taken from
+	/// address_book.
+	pub fn new(store: Arc<dyn NodeStore>, address_book: Arc<PeerAddressBook>, node_id: PublicKey, keys_start_time: (u64, u32), existing_channels: &[ChannelDetails]) -> Self {
+		let mut start_times = HashMap::new();
+		match store.read(&StorageKey::ChannelBackup).map_err(|e| e.to_string()).and_then(|data| match data {
+			Some(data) => parse_backup(&data),
+			None => Ok(Vec::new()),
+		}) {
+			Ok(channels) => {
+				for chan in channels {
+					start_times.insert(chan.channel_id, chan.keys_start_time);
+					// Backups written before we shared the address book may know addresses it doesn't.
+					if address_book.get(&chan.peer_node_id).is_empty() {
+						for addr in chan.peer_addresses.into_iter().rev() {
+							address_book.add(chan.peer_node_id, addr);
+						}
+					}
+				}
+			},
+			Err(e) => println!("WARNING: Failed to read previous channel backup, will overwrite it: {}", e),
+		}
+		for chan in existing_channels {
+			start_times.entry(chan.channel_id).or_insert(None);
+		}
+		Self {
+			store, node_id, keys_start_time,
+			start_times: Mutex::new(start_times),
+			address_book,
+			last_backed_up: Mutex::new(Vec::new()),
+		}
+	}
+
+	fn serialize(&self, channels: &[ChannelDetails]) -> Result<Vec<u8>, std::io::Error> {
+		let funding_txos: HashMap<[u8; 32], OutPoint> = self.store.list_monitors()?.drain(..)
+			.map(|funding_txo| (funding_txo.to_channel_id(), funding_txo)).collect();
+		let mut start_times = self.start_times.lock().unwrap();
+		let mut backup_channels = Vec::new();
+		for chan in channels {
+			// Channels without a monitor haven't been funded yet, so there's nothing to recover.
+			if let Some(funding_txo) = funding_txos.get(&chan.channel_id) {
+				let keys_start_time = *start_times.entry(chan.channel_id).or_insert(Some(self.keys_start_time));
+				backup_channels.push(channel_to_json(&BackedUpChannel {
+					channel_id: chan.channel_id,
+					funding_txo: *funding_txo,
+					peer_node_id: chan.remote_network_id,
+					peer_addresses: self.address_book.get(&chan.remote_network_id),
+					channel_value_satoshis: chan.channel_value_satoshis,
+					keys_start_time,
+				}));
+			}
+		}
+		Ok(serde_json::to_vec_pretty(&serde_json::json!({
+			"version": 1,
+			"node_id": hex_str(&self.node_id.serialize()),
+			"channels": backup_channels,
+		}))?)
+	}
+
+	/// Rewrites the backup if our set of channels, or the addresses we know for their peers, have
+	/// changed since we last wrote it.
+	pub fn update(&self, channels: &[ChannelDetails]) {
+		let mut backed_up: Vec<([u8; 32], Vec<PeerAddress>)> = channels.iter()
+			.map(|chan| (chan.channel_id, self.address_book.get(&chan.remote_network_id))).collect();
+		backed_up.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+		let mut last_backed_up = self.last_backed_up.lock().unwrap();
+		if *last_backed_up == backed_up { return; }
+		match self.serialize(channels).and_then(|data| self.store.write(&StorageKey::ChannelBackup, &data)) {
+			Ok(()) => *last_backed_up = backed_up,
+			Err(e) => println!("WARNING: Failed to write static channel backup: {}", e),
+		}
+	}
+
+	/// Writes the current backup to the given path, for copying somewhere safe.
+	pub fn export(&self, channels: &[ChannelDetails], path: &str) -> Result<(), std::io::Error> {
+		fs::write(path, self.serialize(channels)?)
+	}
+}
+
+/// Each channel we open or accept uses the next key derivation index, so we don't know exactly
+/// which one a backed-up channel used. We search this many from each KeysManager start time.
+const RECOVERY_KEY_SEARCH_DEPTH: usize = 250;
+
+/// Imports into bitcoind the keys that the counterparty's commitment transaction may pay our
+/// balance to, so that once they force-close in response to us not knowing their channel the
+/// funds show up in the wallet, just like a DynamicOutputP2WPKH SpendableOutputs event.
+///
+/// Without option_static_remotekey the to_remote output isn't paid to our payment base key but
+/// to one tweaked by the commitment transaction's per-commitment point, which the peer only tells
+/// us in the data_loss_protect field of its channel_reestablish.
+pub async fn import_recovery_keys(rpc_client: &RPCClient, seed: &[u8; 32], network: Network, logger: Arc<dyn Logger>, chan: &BackedUpChannel, per_commitment_point: &PublicKey) {
+	let (secs, nanos) = match chan.keys_start_time {
+		Some(start_time) => start_time,
+		None => return,
+	};
+	let secp_ctx = Secp256k1::signing_only();
+	let keys = KeysManager::new(seed, network, logger, secs, nanos);
+	for _ in 0..RECOVERY_KEY_SEARCH_DEPTH {
+		let chan_keys = keys.get_channel_keys(false, 0);
+		let key = match chan_utils::derive_private_key(&secp_ctx, per_commitment_point, chan_keys.payment_base_key()) {
+			Ok(key) => key,
+			Err(_) => continue,
+		};
+		let wif = bitcoin::util::key::PrivateKey { key, compressed: true, network }.to_wif();
+		if rpc_client.make_rpc_call("importprivkey", &[&("\"".to_string() + &wif + "\""), "\"rust-lightning channel backup recovery\"", "false"], false).await.is_err() {
+			println!("WARNING: Failed to import a recovery key into bitcoind!");
+			return;
+		}
+	}
+}
@@ -0,0 +1,136 @@
+//! Receives channel monitors replicated from a node started with
+//! --monitor-replica=KEYFILE@host:port and durably stores them in storage_directory_path/monitors,
+//! in the same layout the node itself uses (so the directory can be used to recover the node).
+
+use bitcoin::hashes::{Hash, HashEngine, Hmac, HmacEngine};
+use bitcoin::hashes::sha256::Hash as Sha256Hash;
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::hash_types::Txid;
+
+use rand::{thread_rng, Rng};
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::Duration;
+
+const REPLICATION_ACK: u8 = 1;
+const REPLICATION_NACK: u8 = 0;
+const MAX_MONITOR_LEN: usize = 64 * 1024 * 1024;
+
+fn write_monitor(dir: &str, filename: &str, data: &[u8]) -> Result<(), std::io::Error> {
+	let path = format!("{}/{}", dir, filename);
+	let tmp_path = path.clone() + ".tmp";
+	{
+		let mut f = fs::File::create(&tmp_path)?;
+		f.write_all(data)?;
+		f.sync_all()?;
+	}
+	fs::rename(&tmp_path, &path)?;
+	fs::File::open(Path::new(dir))?.sync_all()
+}
+
+fn handle_conn(mut stream: TcpStream, key: &[u8; 32], dir: &str) -> Result<(), std::io::Error> {
+	stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+	stream.set_write_timeout(Some(Duration::from_secs(30)))?;
+
+	let mut challenge = [0; 32];
+	thread_rng().fill_bytes(&mut challenge);
+	stream.write_all(&challenge)?;
+
+	let mut header = [0; 38];
+	stream.read_exact(&mut header)?;
+	let txid = Txid::from_slice(&header[0..32]).unwrap();
+	let index = ((header[32] as u16) << 8) | header[33] as u16;
+	let len = ((header[34] as usize) << 24) | ((header[35] as usize) << 16) | ((header[36] as usize) << 8) | header[37] as usize;
+	if len > MAX_MONITOR_LEN {
+		return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "monitor too large"));
+	}
+	let mut data = vec![0; len];
+	stream.read_exact(&mut data)?;
+	let mut their_hmac = [0; 32];
+	stream.read_exact(&mut their_hmac)?;
+
+	let mut engine = HmacEngine::<Sha256Hash>::new(key);
+	engine.input(&challenge);
+	engine.input(&header);
+	engine.input(&data);
+	let our_hmac = Hmac::<Sha256Hash>::from_engine(engine).into_inner();
+	if our_hmac.iter().zip(their_hmac.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) != 0 {
+		return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "bad HMAC, check the shared key"));
+	}
+
+	let filename = format!("{}_{}", txid.to_hex(), index);
+	match write_monitor(dir, &filename, &data) {
+		Ok(()) => {
+			stream.write_all(&[REPLICATION_ACK])?;
+			println!("Stored monitor {} ({} bytes)", filename, len);
+			Ok(())
+		},
+		Err(e) => {
+			let _ = stream.write_all(&[REPLICATION_NACK]);
+			Err(e)
+		},
+	}
+}
+
+fn main() {
+	println!("USAGE: monitor_receiver listen_host:port storage_directory_path");
+	let args: Vec<String> = env::args().collect();
+	if args.len() < 3 { return; }
+
+	let monitors_dir = args[2].clone() + "/monitors";
+	if let Err(e) = fs::create_dir_all(&monitors_dir) {
+		println!("Failed to create {}: {}", monitors_dir, e);
+		return;
+	}
+
+	let key_path = args[2].clone() + "/replication_key";
+	let key = match fs::read(&key_path) {
+		Ok(key_bytes) => {
+			if key_bytes.len() != 32 {
+				println!("{} is corrupt (must be exactly 32 bytes)", key_path);
+				return;
+			}
+			let mut key = [0; 32];
+			key.copy_from_slice(&key_bytes);
+			key
+		},
+		Err(_) => {
+			let mut key = [0; 32];
+			thread_rng().fill_bytes(&mut key);
+			// Anyone who can read the key can overwrite our replicated monitors.
+			let written = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&key_path)
+				.and_then(|mut f| { f.write_all(&key)?; f.sync_all() });
+			if let Err(e) = written {
+				println!("Failed to write {}: {}", key_path, e);
+				return;
+			}
+			println!("Generated new shared key in {}, copy it to the node and pass --monitor-replica=KEYFILE@{}", key_path, args[1]);
+			key
+		},
+	};
+
+	let listener = match TcpListener::bind(&args[1]) {
+		Ok(listener) => listener,
+		Err(e) => {
+			println!("Failed to bind {}: {}", args[1], e);
+			return;
+		}
+	};
+	println!("Listening on {}, storing monitors in {}", args[1], monitors_dir);
+	for conn in listener.incoming() {
+		match conn {
+			Ok(stream) => {
+				let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+				if let Err(e) = handle_conn(stream, &key, &monitors_dir) {
+					println!("Failed to receive monitor from {}: {}", peer, e);
+				}
+			},
+			Err(e) => println!("Failed to accept connection: {}", e),
+		}
+	}
+}
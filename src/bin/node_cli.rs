@@ -0,0 +1,47 @@
+//! Sends a single command to a node's control socket and prints the response.
+
+use std::env;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+
+fn main() {
+	let args: Vec<String> = env::args().collect();
+	if args.len() < 3 {
+		println!("USAGE: node_cli control_socket_path command [args...]");
+		println!("eg: node_cli storage_directory_path/control.sock listchannels");
+		println!("Use the command help to list the available commands");
+		std::process::exit(1);
+	}
+
+	let mut stream = match UnixStream::connect(&args[1]) {
+		Ok(stream) => stream,
+		Err(e) => {
+			eprintln!("Failed to connect to {}: {}", args[1], e);
+			std::process::exit(1);
+		}
+	};
+	let command = args[2..].join(" ") + "\n";
+	if let Err(e) = stream.write_all(command.as_bytes()) {
+		eprintln!("Failed to send command: {}", e);
+		std::process::exit(1);
+	}
+
+	let mut response = String::new();
+	if let Err(e) = BufReader::new(&stream).read_line(&mut response) {
+		eprintln!("Failed to read response: {}", e);
+		std::process::exit(1);
+	}
+	match serde_json::from_str::<serde_json::Value>(&response) {
+		Ok(v) => match v["output"].as_str() {
+			Some(output) => print!("{}", output),
+			None => {
+				eprintln!("Malformed response from node: {}", response);
+				std::process::exit(1);
+			}
+		},
+		Err(_) => {
+			eprintln!("Node closed the connection without responding");
+			std::process::exit(1);
+		}
+	}
+}
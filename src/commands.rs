@@ -0,0 +1,396 @@
+use crate::announce::*;
+use crate::archive::*;
+use crate::backup::*;
+use crate::chain_monitor::*;
+use crate::config::*;
+use crate::invoices::*;
+use crate::net::*;
+use crate::payments::*;
+use crate::peer_filter::*;
+use crate::persist::*;
+use crate::reconnect::*;
+use crate::seed::*;
+use crate::utils::*;
+
+use tokio::sync::mpsc;
+
+use bitcoin::secp256k1::key::PublicKey;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::secp256k1;
+
+use rand::{thread_rng, Rng};
+
+use lightning::chain::keysinterface::{KeysInterface, KeysManager};
+use lightning::ln::{router, channelmanager};
+use lightning::ln::channelmanager::{PaymentHash, PaymentPreimage};
+
+use bitcoin::hashes::Hash;
+use bitcoin::hashes::sha256::Hash as Sha256Hash;
+use bitcoin::network::constants::Network;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// What a command's first argument is, for tab completion.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ArgKind {
+	None,
+	NodeId,
+	ChannelId,
+	Command,
+}
+
+pub struct Command {
+	pub name: &'static str,
+	/// The terse form the command was originally typed as, still accepted so existing scripts
+	/// keep working.
+	pub alias: &'static str,
+	pub usage: &'static str,
+	pub help: &'static str,
+	min_args: usize,
+	max_args: usize,
+	pub first_arg: ArgKind,
+	/// Commands which prompt on the terminal (or print secrets) are refused on the control socket.
+	interactive_only: bool,
+}
+
+pub const COMMANDS: &[Command] = &[
+	Command { name: "help", alias: "h", usage: "help [command]", help: "List all commands, or describe the given one",
+		min_args: 0, max_args: 1, first_arg: ArgKind::Command, interactive_only: false },
+	Command { name: "announce", alias: "a", usage: "announce [ip:port] [alias]", help: "Announce our node now, with the configured alias and addresses unless others are given",
+		min_args: 0, max_args: std::usize::MAX, first_arg: ArgKind::None, interactive_only: false },
+	Command { name: "connect", alias: "c", usage: "connect pubkey@host:port", help: "Connect to given host (an IP, hostname or .onion address) and port, with given pubkey for auth",
+		min_args: 1, max_args: 1, first_arg: ArgKind::NodeId, interactive_only: false },
+	Command { name: "openchannel", alias: "n", usage: "openchannel pubkey value_sat [push_msat]", help: "Create a channel with the given connected node (by pubkey), value in satoshis, and push the given msat value",
+		min_args: 2, max_args: 3, first_arg: ArgKind::NodeId, interactive_only: false },
+	Command { name: "closechannel", alias: "k", usage: "closechannel channel_id", help: "Close a channel with the given id",
+		min_args: 1, max_args: 1, first_arg: ArgKind::ChannelId, interactive_only: false },
+	Command { name: "forceclose", alias: "f", usage: "forceclose channel_id|all", help: "Force close the given channel, or all channels, closing to chain",
+		min_args: 1, max_args: 1, first_arg: ArgKind::ChannelId, interactive_only: false },
+	Command { name: "listpeers", alias: "l p", usage: "listpeers", help: "List the node_ids of all connected peers",
+		min_args: 0, max_args: 0, first_arg: ArgKind::None, interactive_only: false },
+	Command { name: "listchannels", alias: "l c", usage: "listchannels", help: "List details about all channels",
+		min_args: 0, max_args: 0, first_arg: ArgKind::None, interactive_only: false },
+	Command { name: "listarchived", alias: "l a", usage: "listarchived", help: "List closed channels whose monitors have been archived",
+		min_args: 0, max_args: 0, first_arg: ArgKind::None, interactive_only: false },
+	Command { name: "pay", alias: "s", usage: "pay invoice [amount_msat]", help: "Send payment to an invoice, with an amount in whole msat only if its not in the invoice",
+		min_args: 1, max_args: 2, first_arg: ArgKind::None, interactive_only: false },
+	Command { name: "quote", alias: "q", usage: "quote invoice [amount_msat] [probe]", help: "Quote the fees and CLTV for paying an invoice, optionally probing the route with a fake payment",
+		min_args: 1, max_args: 3, first_arg: ArgKind::None, interactive_only: false },
+	Command { name: "decodeinvoice", alias: "d", usage: "decodeinvoice invoice", help: "Decode an invoice and print all of its fields",
+		min_args: 1, max_args: 1, first_arg: ArgKind::None, interactive_only: false },
+	Command { name: "invoice", alias: "p", usage: "invoice amount_msat", help: "Gets a new invoice for receiving funds for the given amount in msat",
+		min_args: 1, max_args: 1, first_arg: ArgKind::None, interactive_only: false },
+	Command { name: "exportbackup", alias: "b", usage: "exportbackup path", help: "Export a static channel backup to the given path",
+		min_args: 1, max_args: 1, first_arg: ArgKind::None, interactive_only: false },
+	Command { name: "setfees", alias: "r", usage: "setfees proportional_millionths", help: "Set the proportional forwarding fee for channels opened after the next restart",
+		min_args: 1, max_args: 1, first_arg: ArgKind::None, interactive_only: false },
+	Command { name: "seedpassphrase", alias: "e seed", usage: "seedpassphrase", help: "Set or change the passphrase key_seed is encrypted with (interactive shell only)",
+		min_args: 0, max_args: 0, first_arg: ArgKind::None, interactive_only: true },
+	Command { name: "showmnemonic", alias: "m seed", usage: "showmnemonic", help: "Print the BIP39 mnemonic backup of key_seed (interactive shell only)",
+		min_args: 0, max_args: 0, first_arg: ArgKind::None, interactive_only: true },
+];
+
+/// Finds the command the given words start with, by name or alias, returning it and its arguments.
+pub fn find_command<'a, 'b>(words: &'b [&'a str]) -> Option<(&'static Command, &'b [&'a str])> {
+	for command in COMMANDS {
+		if words[0] == command.name {
+			return Some((command, &words[1..]));
+		}
+		let alias: Vec<&str> = command.alias.split(' ').collect();
+		if words.len() >= alias.len() && words[..alias.len()] == alias[..] {
+			return Some((command, &words[alias.len()..]));
+		}
+	}
+	None
+}
+
+pub fn command_help() -> String {
+	let mut res = String::new();
+	for command in COMMANDS {
+		res += &format!("'{}' {}\n", command.usage, command.help);
+	}
+	res
+}
+
+fn parse_pubkey(arg: &str) -> Result<PublicKey, String> {
+	match hex_to_compressed_pubkey(arg) {
+		Some(pk) => Ok(pk),
+		None => Err(format!("Bad PubKey {}, should be 33 bytes of hex", arg)),
+	}
+}
+
+fn parse_channel_id(arg: &str) -> Result<[u8; 32], String> {
+	match hex_to_vec(arg) {
+		Some(ref id) if id.len() == 32 => {
+			let mut channel_id = [0; 32];
+			channel_id.copy_from_slice(id);
+			Ok(channel_id)
+		},
+		_ => Err(format!("Bad channel_id {}, should be 32 bytes of hex", arg)),
+	}
+}
+
+fn parse_u64(name: &str, arg: &str) -> Result<u64, String> {
+	arg.parse().map_err(|_| format!("Couldn't parse {} into {}", arg, name))
+}
+
+/// Everything the shell commands operate on, shared between the interactive shell and the control
+/// socket.
+pub struct CommandHandler {
+	pub network: Network,
+	pub secp_ctx: Secp256k1<secp256k1::All>,
+	pub keys: Arc<KeysManager>,
+	pub our_node_seed: [u8; 32],
+	pub store: Arc<dyn NodeStore>,
+	pub peer_manager: FilteredPeerManager,
+	pub channel_manager: channelmanager::SimpleArcChannelManager<crate::ChannelMonitor, ChainInterface, FeeEstimator>,
+	pub router: Arc<router::Router>,
+	pub payments: Arc<PaymentTracker>,
+	pub payment_preimages: Arc<Mutex<HashMap<PaymentHash, PaymentPreimage>>>,
+	pub channel_backup: Arc<ChannelBackup>,
+	pub address_book: Arc<PeerAddressBook>,
+	pub event_notify: mpsc::Sender<()>,
+	pub max_total_channel_capacity_sat: Option<u64>,
+	/// A SOCKS5 proxy to make outbound connections through.
+	pub proxy: Option<SocketAddr>,
+	pub announcement: AnnouncementSettings,
+}
+
+impl CommandHandler {
+	/// Connects to the given peer, starting the handshake in the background, and remembers the
+	/// address for reconnecting.
+	pub async fn connect(&self, node_id: PublicKey, addr: PeerAddress) -> Result<(), String> {
+		connect_outbound(self.peer_manager.clone(), self.event_notify.clone(), node_id, &addr, self.proxy).await?;
+		self.address_book.add(node_id, addr);
+		Ok(())
+	}
+
+	/// Opens a channel of value_sat with the given (connected) peer, pushing push_msat to them.
+	pub fn open_channel(&self, node_id: PublicKey, value_sat: u64, push_msat: u64) -> Result<(), String> {
+		let total_capacity: u64 = self.channel_manager.list_channels().iter().map(|chan| chan.channel_value_satoshis).sum();
+		if let Some(max) = self.max_total_channel_capacity_sat {
+			match total_capacity.checked_add(value_sat) {
+				Some(new_capacity) if new_capacity <= max => {},
+				Some(new_capacity) => return Err(format!("Refusing to open channel, it would bring our total channel capacity to {} sat, above channels.max_total_capacity_satoshis of {} sat", new_capacity, max)),
+				None => return Err(format!("Refusing to open channel, {} sat is above channels.max_total_capacity_satoshis of {} sat", value_sat, max)),
+			}
+		}
+		let res = self.channel_manager.create_channel(node_id, value_sat, push_msat, 0, None);
+		let _ = self.event_notify.clone().try_send(());
+		res.map_err(|e| format!("Failed to open channel: {:?}!", e))
+	}
+
+	/// Sets the proportional forwarding fee of channels opened after the next restart. rust-lightning
+	/// fixes each channel's fee when it is opened, and picks the base fee and CLTV delta itself, so
+	/// existing channels can't be changed and no channel_updates are sent.
+	pub fn set_fees(&self, proportional_millionths: u32) -> Result<(), String> {
+		write_fee_policy(&*self.store, proportional_millionths)
+	}
+
+	/// Cooperatively closes the given channel.
+	pub fn close_channel(&self, channel_id: &[u8; 32]) -> Result<(), String> {
+		self.channel_manager.close_channel(channel_id).map_err(|e| format!("Failed to close channel: {:?}", e))?;
+		let _ = self.event_notify.clone().try_send(());
+		Ok(())
+	}
+
+	/// Pays an invoice, returning its payment_hash, the amount sent, the number of parts which went
+	/// out and whether some others failed to (see PaymentTracker::send_payment).
+	pub fn pay_invoice(&self, invoice_str: &str, amt_arg: Option<&str>) -> Result<(PaymentHash, u64, usize, bool), String> {
+		let (invoice, payment_hash, amt, final_cltv) = parse_invoice_to_pay(invoice_str, amt_arg, self.network)?;
+		let (parts, incomplete) = self.payments.send_payment(&self.router, &self.channel_manager, &invoice, payment_hash, amt, final_cltv)?;
+		let _ = self.event_notify.clone().try_send(());
+		Ok((payment_hash, amt, parts, incomplete))
+	}
+
+	/// Creates an invoice for receiving amt_msat, returning its payment_hash and the encoded
+	/// invoice.
+	pub fn create_invoice(&self, amt_msat: u64) -> Result<(PaymentHash, String), String> {
+		let mut payment_preimage = [0; 32];
+		thread_rng().fill_bytes(&mut payment_preimage);
+		let payment_hash = Sha256Hash::hash(&payment_preimage);
+		//TODO: Store this on disk somewhere!
+		self.payment_preimages.lock().unwrap().insert(PaymentHash(payment_hash.into_inner()), PaymentPreimage(payment_preimage));
+
+		let invoice = lightning_invoice::InvoiceBuilder::new(network_to_currency(self.network))
+			.payment_hash(payment_hash).description("rust-lightning-bitcoinrpc invoice".to_string())
+			//.route(chans)
+			.amount_pico_btc(amt_msat.checked_mul(10).ok_or("Amount too large")?)
+			.current_timestamp()
+			.build_signed(|msg_hash| {
+				self.secp_ctx.sign_recoverable(msg_hash, &self.keys.get_node_secret())
+			}).map_err(|e| format!("Error creating invoice: {:?}", e))?;
+		Ok((PaymentHash(payment_hash.into_inner()), invoice.to_string()))
+	}
+
+	/// Runs a single command line, returning its output. Commands which prompt on the terminal
+	/// (or print secrets) are refused unless interactive is set.
+	pub async fn run_command(&self, line: &str, interactive: bool) -> String {
+		let words: Vec<&str> = line.split_whitespace().collect();
+		if words.is_empty() { return String::new(); }
+		let (command, args) = match find_command(&words) {
+			Some(res) => res,
+			None => return format!("Unknown command {}, try help\n", words[0]),
+		};
+		if args.len() < command.min_args || args.len() > command.max_args {
+			return format!("Usage: {}\n", command.usage);
+		}
+		if command.interactive_only && !interactive {
+			return format!("{} is only available in the interactive shell\n", command.name);
+		}
+		match self.execute(command, args).await {
+			Ok(out) => out,
+			Err(e) => e + "\n",
+		}
+	}
+
+	async fn execute(&self, command: &Command, args: &[&str]) -> Result<String, String> {
+		let mut out = String::new();
+		macro_rules! outln {
+			($($arg: tt)*) => {
+				out += &format!($($arg)*);
+				out.push('\n');
+			}
+		}
+		match command.name {
+			"help" => match args.first() {
+				Some(name) => match find_command(&[*name]) {
+					Some((command, _)) => outln!("Usage: {}\n{}\nAlso available as: {}", command.usage, command.help, command.alias),
+					None => outln!("Unknown command {}", name),
+				},
+				None => out += &command_help(),
+			},
+			"announce" => {
+				let mut settings = self.announcement.clone();
+				let mut args = args;
+				// An ip:port before the alias replaces the configured address of the same type.
+				if let Some(sockaddr) = args.first().and_then(|arg| arg.parse::<SocketAddr>().ok()) {
+					let addr = PeerAddress::Socket(sockaddr).to_net_address().unwrap();
+					settings.addresses.retain(|known| std::mem::discriminant(known) != std::mem::discriminant(&addr));
+					settings.addresses.push(addr);
+					sort_addresses(&mut settings.addresses)?;
+					args = &args[1..];
+				}
+				if !args.is_empty() {
+					settings.alias = args.join(" ");
+					if settings.alias.len() > 32 {
+						return Err("alias must be no longer than 32 bytes".to_string());
+					}
+				}
+				broadcast_announcement(&self.channel_manager, &settings);
+				let _ = self.event_notify.clone().try_send(());
+			},
+			"connect" => {
+				let mut parts = args[0].splitn(2, '@');
+				let pk = parse_pubkey(parts.next().unwrap())?;
+				let addr: PeerAddress = parts.next().ok_or("Invalid line, should be connect pubkey@host:port")?.parse()?;
+				out += &format!("Attempting to connect to {}...", addr);
+				match self.connect(pk, addr).await {
+					Ok(()) => outln!("connected, initiating handshake!"),
+					Err(e) => outln!("{}", e),
+				}
+			},
+			"openchannel" => {
+				let pk = parse_pubkey(args[0])?;
+				let value = parse_u64("a value", args[1])?;
+				let push = match args.get(2) {
+					Some(push_str) => parse_u64("a push value", push_str)?,
+					None => 0,
+				};
+				self.open_channel(pk, value, push)?;
+				outln!("Channel created, sending open_channel!");
+			},
+			"closechannel" => {
+				self.close_channel(&parse_channel_id(args[0])?)?;
+				outln!("Ok, channel closing!");
+			},
+			"forceclose" => {
+				if args[0] == "all" {
+					self.channel_manager.force_close_all_channels();
+				} else {
+					self.channel_manager.force_close_channel(&parse_channel_id(args[0])?);
+				}
+			},
+			"listpeers" => {
+				let mut nodes = String::new();
+				for node_id in self.peer_manager.get_peer_node_ids() {
+					nodes += &format!("{}, ", hex_str(&node_id.serialize()));
+				}
+				outln!("Connected nodes: {}", nodes);
+			},
+			"listchannels" => {
+				outln!("All channels:");
+				for chan_info in self.channel_manager.list_channels() {
+					if let Some(short_id) = chan_info.short_channel_id {
+						outln!("id: {}, short_id: {}, peer: {}, value: {} sat, live: {}", hex_str(&chan_info.channel_id[..]), short_id, hex_str(&chan_info.remote_network_id.serialize()), chan_info.channel_value_satoshis, chan_info.is_live);
+					} else {
+						outln!("id: {}, not yet confirmed, peer: {}, value: {} sat, live: {}", hex_str(&chan_info.channel_id[..]), hex_str(&chan_info.remote_network_id.serialize()), chan_info.channel_value_satoshis, chan_info.is_live);
+					}
+				}
+			},
+			"listarchived" => out += &format_archived_monitors(&self.store),
+			"pay" => {
+				let (_, amt, parts, incomplete) = self.pay_invoice(args[0], args.get(1).cloned())?;
+				if incomplete {
+					outln!("Only {} part(s) of the {} msat payment could be sent, it will be re-routed once the recipient returns them", parts, amt);
+				} else {
+					outln!("Sending {} msat in {} part(s)", amt, parts);
+				}
+			},
+			"quote" => {
+				let probe = args.len() > 1 && args[args.len() - 1] == "probe";
+				let amt_args = &args[1..args.len() - if probe { 1 } else { 0 }];
+				if amt_args.len() > 1 {
+					return Err(format!("Usage: {}", command.usage));
+				}
+				let (invoice, _, amt, final_cltv) = parse_invoice_to_pay(args[0], amt_args.first().cloned(), self.network)?;
+				let route = self.payments.quote(&self.router, &self.channel_manager, &invoice, amt, final_cltv)?;
+				out += &format_route_quote(&route, amt);
+				if probe {
+					let probe_hash = self.payments.send_probe(&self.channel_manager, &route)?;
+					outln!("Sent probe with payment_hash {}", hex_str(&probe_hash.0));
+					let _ = self.event_notify.clone().try_send(());
+				}
+			},
+			"decodeinvoice" => out += &format_decoded_invoice(args[0]),
+			"invoice" => {
+				let (payment_hash, invoice) = self.create_invoice(parse_u64("an amount", args[0])?)?;
+				outln!("payment_hash: {}", hex_str(&payment_hash.0));
+				outln!("Invoice: {}", invoice);
+			},
+			"exportbackup" => {
+				self.channel_backup.export(&self.channel_manager.list_channels(), args[0])
+					.map_err(|e| format!("Failed to write channel backup: {}", e))?;
+				outln!("Wrote channel backup to {}", args[0]);
+			},
+			"setfees" => {
+				let fee = parse_u64("a fee", args[0])?;
+				let fee = u32::try_from(fee).map_err(|_| format!("Bad fee {}, must be at most {}", fee, std::u32::MAX))?;
+				self.set_fees(fee)?;
+				outln!("Channels opened after the next restart will charge {} millionths of each forwarded payment, existing channels keep their fees", fee);
+			},
+			"seedpassphrase" => {
+				match self.store.read(&StorageKey::KeySeed) {
+					Ok(Some(ref data)) if is_encrypted(data) => {
+						PassphraseSource::Prompt.read("Current key_seed passphrase: ").and_then(|old| decrypt_seed(data, &old))?;
+					},
+					Ok(_) => {},
+					Err(e) => return Err(format!("Failed to read key_seed: {}", e)),
+				}
+				let passphrase = prompt_new_passphrase()?;
+				if passphrase.is_empty() && self.network == Network::Bitcoin {
+					return Err("key_seed must stay encrypted on mainnet".to_string());
+				}
+				let data = if passphrase.is_empty() { self.our_node_seed.to_vec() } else { encrypt_seed(&self.our_node_seed, &passphrase) };
+				self.store.write(&StorageKey::KeySeed, &data).map_err(|e| format!("Failed to write key_seed: {}", e))?;
+				outln!("Updated key_seed {}", if passphrase.is_empty() { "(now unencrypted!)" } else { "passphrase" });
+			},
+			"showmnemonic" => outln!("Seed mnemonic: {}", seed_to_mnemonic(&self.our_node_seed)),
+			_ => unreachable!(),
+		}
+		Ok(out)
+	}
+}
@@ -0,0 +1,345 @@
+use crate::announce::*;
+use crate::net::*;
+use crate::peer_filter::*;
+use crate::persist::*;
+use crate::utils::*;
+
+use lightning::util::config::UserConfig;
+use lightning::util::logger::Level;
+
+use bitcoin::secp256k1::key::PublicKey;
+
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::fs;
+use std::net::SocketAddr;
+
+/// The maximum number of parts we'll split an outbound payment into if it doesn't fit in a single
+/// channel.
+const DEFAULT_MAX_PAYMENT_PATHS: usize = 4;
+/// The number of times we'll (re-)route a payment before giving up on it.
+const DEFAULT_MAX_PAYMENT_ATTEMPTS: usize = 3;
+/// We refuse to send payments whose routing fees exceed the larger of these two limits.
+const DEFAULT_MAX_PAYMENT_FEE_BASE_MSAT: u64 = 1000;
+const DEFAULT_MAX_PAYMENT_FEE_PROPORTIONAL_MILLIONTHS: u64 = 5000;
+const DEFAULT_FEE_PROPORTIONAL_MILLIONTHS: u32 = 10;
+const DEFAULT_ANNOUNCE_CHANNELS: bool = false;
+const DEFAULT_LISTEN_PORT: u16 = 9735;
+const DEFAULT_MAX_INBOUND_PEERS: usize = 100;
+const DEFAULT_MAX_INBOUND_PER_IP_PER_MINUTE: usize = 10;
+
+/// rust-lightning refuses to open channels with a to_self_delay outside of this range.
+const MIN_TO_SELF_DELAY: u16 = 144;
+const MAX_TO_SELF_DELAY: u16 = 2016;
+
+/// Everything configurable about the node, read from config.toml in the storage directory.
+pub struct NodeConfig {
+	/// user:pass@host:port of bitcoind's RPC interface.
+	pub rpc: Option<String>,
+	/// Where we accept inbound peer connections, empty for a private node which only connects out.
+	pub listen: Vec<SocketAddr>,
+	/// What we put in our node_announcement, including the public addresses we can be reached at.
+	pub announcement: AnnouncementSettings,
+	/// A SOCKS5 proxy (eg Tor) to make all outbound connections through.
+	pub proxy: Option<SocketAddr>,
+	pub peer_policy: PeerPolicy,
+	pub channel_policy: ChannelAcceptancePolicy,
+	pub user_config: UserConfig,
+	/// Whether fees.proportional_millionths was given with --set, in which case it takes
+	/// precedence over the fee set with setfees.
+	pub fee_overridden: bool,
+	/// The most we'll let the channels we open add up to, required on mainnet.
+	pub max_total_channel_capacity_sat: Option<u64>,
+	/// Whether the operator has confirmed (in the config file) that they want to run on mainnet.
+	pub mainnet_confirmed: bool,
+	pub max_payment_paths: usize,
+	pub max_payment_attempts: usize,
+	pub max_payment_fee_base_msat: u64,
+	pub max_payment_fee_proportional_millionths: u64,
+	/// The most verbose level of rust-lightning log messages we print.
+	pub log_level: Level,
+	/// Whether to print the (very noisy) logs of every gossip message we receive.
+	pub log_gossip: bool,
+	/// Where to serve the JSON-RPC API, if anywhere.
+	pub api_listen: Option<SocketAddr>,
+	/// user:password for the JSON-RPC API. If unset, a random password is written to api_cookie in
+	/// the storage directory on each start.
+	pub api_auth: Option<String>,
+}
+
+impl Default for NodeConfig {
+	fn default() -> Self {
+		let mut user_config = UserConfig::default();
+		user_config.channel_options.fee_proportional_millionths = DEFAULT_FEE_PROPORTIONAL_MILLIONTHS;
+		user_config.channel_options.announced_channel = DEFAULT_ANNOUNCE_CHANNELS;
+		user_config.own_channel_config.minimum_depth = 1;
+		Self {
+			rpc: None,
+			listen: vec![SocketAddr::new("::".parse().unwrap(), DEFAULT_LISTEN_PORT)],
+			announcement: AnnouncementSettings { alias: String::new(), color: [0, 0, 0], addresses: Vec::new(), auto: true },
+			proxy: None,
+			peer_policy: PeerPolicy {
+				max_inbound: DEFAULT_MAX_INBOUND_PEERS,
+				max_inbound_per_ip_per_minute: DEFAULT_MAX_INBOUND_PER_IP_PER_MINUTE,
+				allow_node_ids: Vec::new(),
+				deny_node_ids: Vec::new(),
+				allow_ips: Vec::new(),
+				deny_ips: Vec::new(),
+			},
+			channel_policy: ChannelAcceptancePolicy {
+				min_funding_satoshis: 0,
+				max_funding_satoshis: None,
+				allow_node_ids: Vec::new(),
+				max_pending_per_peer: None,
+				max_to_self_delay: MAX_TO_SELF_DELAY,
+				max_total_capacity_satoshis: None,
+			},
+			user_config,
+			fee_overridden: false,
+			max_total_channel_capacity_sat: None,
+			mainnet_confirmed: false,
+			max_payment_paths: DEFAULT_MAX_PAYMENT_PATHS,
+			max_payment_attempts: DEFAULT_MAX_PAYMENT_ATTEMPTS,
+			max_payment_fee_base_msat: DEFAULT_MAX_PAYMENT_FEE_BASE_MSAT,
+			max_payment_fee_proportional_millionths: DEFAULT_MAX_PAYMENT_FEE_PROPORTIONAL_MILLIONTHS,
+			log_level: Level::Trace,
+			log_gossip: false,
+			api_listen: None,
+			api_auth: None,
+		}
+	}
+}
+
+/// Reads the proportional fee set with setfees, which takes precedence over
+/// fees.proportional_millionths so that it outlives restarts.
+pub fn read_fee_policy(store: &dyn NodeStore) -> Result<Option<u32>, String> {
+	let data = match store.read(&StorageKey::FeePolicy) {
+		Ok(Some(data)) => data,
+		Ok(None) => return Ok(None),
+		Err(e) => return Err(format!("Failed to read fee_policy: {}", e)),
+	};
+	serde_json::from_slice::<serde_json::Value>(&data).ok()
+		.and_then(|v| v["proportional_millionths"].as_u64())
+		.and_then(|fee| TryFrom::try_from(fee).ok())
+		.map(Some)
+		.ok_or_else(|| "fee_policy is corrupt, fix or remove it".to_string())
+}
+
+pub fn write_fee_policy(store: &dyn NodeStore, proportional_millionths: u32) -> Result<(), String> {
+	let data = serde_json::json!({ "proportional_millionths": proportional_millionths });
+	store.write(&StorageKey::FeePolicy, &serde_json::to_vec(&data).unwrap())
+		.map_err(|e| format!("Failed to write fee_policy: {}", e))
+}
+
+/// Where we look for the config file if --config= isn't given, ie config.toml in the storage
+/// directory (or None for in-memory storage).
+pub fn default_config_path(storage_path: &str) -> Option<String> {
+	storage_directory(storage_path).map(|dir| format!("{}/config.toml", dir))
+}
+
+fn int<T: TryFrom<i64> + PartialOrd + Display>(name: &str, value: &toml::Value, min: T, max: T) -> Result<T, String> {
+	let v = value.as_integer().ok_or_else(|| format!("{} must be an integer, not {}", name, value))?;
+	match T::try_from(v) {
+		Ok(res) if res >= min && res <= max => Ok(res),
+		_ => Err(format!("{} must be between {} and {}, not {}", name, min, max, v)),
+	}
+}
+
+fn boolean(name: &str, value: &toml::Value) -> Result<bool, String> {
+	value.as_bool().ok_or_else(|| format!("{} must be true or false, not {}", name, value))
+}
+
+fn string<'a>(name: &str, value: &'a toml::Value) -> Result<&'a str, String> {
+	value.as_str().ok_or_else(|| format!("{} must be a string, not {}", name, value))
+}
+
+fn string_list<'a>(name: &str, value: &'a toml::Value) -> Result<Vec<&'a str>, String> {
+	let list = value.as_array().ok_or_else(|| format!("{} must be a list of strings, not {}", name, value))?;
+	list.iter().map(|v| string(name, v)).collect()
+}
+
+/// Either a single string or a list of them, for settings which usually only have one value.
+fn one_or_more_strings<'a>(name: &str, value: &'a toml::Value) -> Result<Vec<&'a str>, String> {
+	match value {
+		toml::Value::String(s) => Ok(vec![&s[..]]),
+		_ => string_list(name, value),
+	}
+}
+
+fn node_id_list(name: &str, value: &toml::Value) -> Result<Vec<PublicKey>, String> {
+	string_list(name, value)?.iter().map(|s| match hex_to_compressed_pubkey(s) {
+		Some(node_id) => Ok(node_id),
+		None => Err(format!("{} must only contain hex node ids, not {}", name, s)),
+	}).collect()
+}
+
+fn ip_range_list(name: &str, value: &toml::Value) -> Result<Vec<IpRange>, String> {
+	string_list(name, value)?.iter().map(|s| IpRange::parse(s)
+		.ok_or_else(|| format!("{} must only contain IP addresses or CIDR ranges like 10.0.0.0/8, not {}", name, s))).collect()
+}
+
+fn parse_log_level(name: &str, value: &toml::Value) -> Result<Level, String> {
+	match string(name, value)?.to_ascii_lowercase().as_str() {
+		"off" => Ok(Level::Off),
+		"error" => Ok(Level::Error),
+		"warn" => Ok(Level::Warn),
+		"info" => Ok(Level::Info),
+		"debug" => Ok(Level::Debug),
+		"trace" => Ok(Level::Trace),
+		level => Err(format!("{} must be one of off, error, warn, info, debug or trace, not {}", name, level)),
+	}
+}
+
+fn apply_setting(config: &mut NodeConfig, section: &str, key: &str, value: &toml::Value) -> Result<(), String> {
+	let name = format!("{}.{}", section, key);
+	let name = &name[..];
+	match (section, key) {
+		("bitcoind", "rpc") => {
+			let rpc = string(name, value)?;
+			if rpc.split('@').count() != 2 {
+				return Err(format!("{} must be of the form user:pass@host:port", name));
+			}
+			config.rpc = Some(rpc.to_string());
+		},
+
+		("network", "listen") => config.listen = one_or_more_strings(name, value)?.iter().map(|s| s.parse()
+			.map_err(|_| format!("{} must be ip:ports to listen on, eg \"[::]:9735\" or [\"0.0.0.0:9735\", \"[::1]:9735\"], not {}", name, s))).collect::<Result<_, _>>()?,
+		("network", "public_addresses") => {
+			config.announcement.addresses = one_or_more_strings(name, value)?.iter().map(|s| s.parse::<PeerAddress>().ok()
+				.and_then(|addr| addr.to_net_address())
+				.ok_or_else(|| format!("{} must be the public ip:ports or .onion:ports we can be reached at, not {}", name, s))).collect::<Result<_, _>>()?;
+			sort_addresses(&mut config.announcement.addresses).map_err(|e| format!("{}: {}", name, e))?;
+		},
+		("network", "proxy") => config.proxy = Some(string(name, value)?.parse()
+			.map_err(|_| format!("{} must be the ip:port of a SOCKS5 proxy, eg \"127.0.0.1:9050\" for Tor", name))?),
+
+		("channels", "minimum_depth") => config.user_config.own_channel_config.minimum_depth = int(name, value, 1, std::u32::MAX)?,
+		("channels", "to_self_delay") => config.user_config.own_channel_config.our_to_self_delay = int(name, value, MIN_TO_SELF_DELAY, MAX_TO_SELF_DELAY)?,
+		("channels", "htlc_minimum_msat") => config.user_config.own_channel_config.our_htlc_minimum_msat = int(name, value, 0, std::u64::MAX)?,
+		("channels", "announce") => config.user_config.channel_options.announced_channel = boolean(name, value)?,
+		("channels", "commit_upfront_shutdown_pubkey") => config.user_config.channel_options.commit_upfront_shutdown_pubkey = boolean(name, value)?,
+		("channels", "max_total_capacity_satoshis") => config.max_total_channel_capacity_sat = Some(int(name, value, 0, std::u64::MAX)?),
+
+		("channel_limits", "min_funding_satoshis") => config.user_config.peer_channel_config_limits.min_funding_satoshis = int(name, value, 0, std::u64::MAX)?,
+		("channel_limits", "max_htlc_minimum_msat") => config.user_config.peer_channel_config_limits.max_htlc_minimum_msat = int(name, value, 0, std::u64::MAX)?,
+		("channel_limits", "min_max_htlc_value_in_flight_msat") => config.user_config.peer_channel_config_limits.min_max_htlc_value_in_flight_msat = int(name, value, 0, std::u64::MAX)?,
+		("channel_limits", "max_channel_reserve_satoshis") => config.user_config.peer_channel_config_limits.max_channel_reserve_satoshis = int(name, value, 0, std::u64::MAX)?,
+		("channel_limits", "min_max_accepted_htlcs") => config.user_config.peer_channel_config_limits.min_max_accepted_htlcs = int(name, value, 0, 483)?,
+		("channel_limits", "min_dust_limit_satoshis") => config.user_config.peer_channel_config_limits.min_dust_limit_satoshis = int(name, value, 0, std::u64::MAX)?,
+		("channel_limits", "max_dust_limit_satoshis") => config.user_config.peer_channel_config_limits.max_dust_limit_satoshis = int(name, value, 0, std::u64::MAX)?,
+		("channel_limits", "max_minimum_depth") => config.user_config.peer_channel_config_limits.max_minimum_depth = int(name, value, 1, std::u32::MAX)?,
+		("channel_limits", "force_announced_channel_preference") => config.user_config.peer_channel_config_limits.force_announced_channel_preference = boolean(name, value)?,
+
+		("fees", "proportional_millionths") => config.user_config.channel_options.fee_proportional_millionths = int(name, value, 0, std::u32::MAX)?,
+
+		("payments", "max_paths") => config.max_payment_paths = int(name, value, 1, std::usize::MAX)?,
+		("payments", "max_attempts") => config.max_payment_attempts = int(name, value, 1, std::usize::MAX)?,
+		("payments", "max_fee_base_msat") => config.max_payment_fee_base_msat = int(name, value, 0, std::u64::MAX)?,
+		("payments", "max_fee_proportional_millionths") => config.max_payment_fee_proportional_millionths = int(name, value, 0, std::u64::MAX)?,
+
+		("peers", "max_inbound") => config.peer_policy.max_inbound = int(name, value, 0, std::usize::MAX)?,
+		("peers", "max_inbound_per_ip_per_minute") => config.peer_policy.max_inbound_per_ip_per_minute = int(name, value, 1, std::usize::MAX)?,
+		("peers", "allow_node_ids") => config.peer_policy.allow_node_ids = node_id_list(name, value)?,
+		("peers", "deny_node_ids") => config.peer_policy.deny_node_ids = node_id_list(name, value)?,
+		("peers", "allow_ips") => config.peer_policy.allow_ips = ip_range_list(name, value)?,
+		("peers", "deny_ips") => config.peer_policy.deny_ips = ip_range_list(name, value)?,
+
+		("announcement", "alias") => {
+			let alias = string(name, value)?;
+			if alias.len() > 32 {
+				return Err(format!("{} must be no longer than 32 bytes", name));
+			}
+			config.announcement.alias = alias.to_string();
+		},
+		("announcement", "color") => config.announcement.color = parse_color(string(name, value)?)
+			.ok_or_else(|| format!("{} must be an RGB color like \"#3399ff\"", name))?,
+		("announcement", "auto") => config.announcement.auto = boolean(name, value)?,
+
+		("inbound_channels", "max_funding_satoshis") => config.channel_policy.max_funding_satoshis = Some(int(name, value, 0, std::u64::MAX)?),
+		("inbound_channels", "allow_node_ids") => config.channel_policy.allow_node_ids = node_id_list(name, value)?,
+		("inbound_channels", "max_pending_per_peer") => config.channel_policy.max_pending_per_peer = Some(int(name, value, 1, std::usize::MAX)?),
+		("inbound_channels", "max_to_self_delay") => config.channel_policy.max_to_self_delay = int(name, value, MIN_TO_SELF_DELAY, MAX_TO_SELF_DELAY)?,
+
+		("mainnet", "confirmed") => config.mainnet_confirmed = boolean(name, value)?,
+
+		("logging", "level") => config.log_level = parse_log_level(name, value)?,
+		("logging", "gossip") => config.log_gossip = boolean(name, value)?,
+
+		("api", "listen") => config.api_listen = Some(string(name, value)?.parse()
+			.map_err(|_| format!("{} must be an ip:port to serve the API on, eg \"127.0.0.1:9736\"", name))?),
+		("api", "auth") => {
+			let auth = string(name, value)?;
+			if auth.split(':').count() != 2 {
+				return Err(format!("{} must be of the form user:password", name));
+			}
+			config.api_auth = Some(auth.to_string());
+		},
+
+		_ => return Err(format!("Unknown setting {}", name)),
+	}
+	Ok(())
+}
+
+/// Parses the value of a --set=section.key=value override. Anything that isn't a valid TOML
+/// value is taken as a string, so that eg --set=bitcoind.rpc=user:pass@host:port works unquoted.
+fn parse_override(spec: &str) -> Result<(String, String, toml::Value), String> {
+	let mut parts = spec.splitn(2, '=');
+	let name = parts.next().unwrap();
+	let value = parts.next().ok_or_else(|| format!("--set must be of the form section.key=value, not {}", spec))?;
+	let mut name_parts = name.splitn(2, '.');
+	let section = name_parts.next().unwrap();
+	let key = name_parts.next().ok_or_else(|| format!("--set must be of the form section.key=value, not {}", spec))?;
+	let value = match format!("v = {}", value).parse::<toml::Value>() {
+		Ok(toml::Value::Table(mut table)) => table.remove("v").unwrap(),
+		_ => toml::Value::String(value.to_string()),
+	};
+	Ok((section.to_string(), key.to_string(), value))
+}
+
+/// Reads the config file at path (if it exists), then applies the given --set= overrides.
+pub fn load_config(path: Option<&str>, overrides: &[String]) -> Result<NodeConfig, String> {
+	let mut config = NodeConfig::default();
+	if let Some(path) = path {
+		match fs::read_to_string(path) {
+			Ok(contents) => {
+				let table = match contents.parse::<toml::Value>() {
+					Ok(toml::Value::Table(table)) => table,
+					Ok(_) => return Err(format!("{} must be a TOML table", path)),
+					Err(e) => return Err(format!("Failed to parse {}: {}", path, e)),
+				};
+				for (section, settings) in table.iter() {
+					let settings = settings.as_table().ok_or_else(|| format!("{}: {} must be a [section] of settings", path, section))?;
+					for (key, value) in settings.iter() {
+						apply_setting(&mut config, section, key, value).map_err(|e| format!("{}: {}", path, e))?;
+					}
+				}
+			},
+			Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {},
+			Err(e) => return Err(format!("Failed to read {}: {}", path, e)),
+		}
+	}
+	for spec in overrides {
+		let (section, key, value) = parse_override(spec)?;
+		apply_setting(&mut config, &section, &key, &value).map_err(|e| format!("--set={}: {}", spec, e))?;
+		if section == "fees" && key == "proportional_millionths" {
+			config.fee_overridden = true;
+		}
+	}
+
+	// rust-lightning enforces this too, but checking it ourselves means the rejection is logged
+	// alongside the rest of our channel policy's.
+	config.channel_policy.min_funding_satoshis = config.user_config.peer_channel_config_limits.min_funding_satoshis;
+	if config.channel_policy.max_funding_satoshis.map(|max| max < config.channel_policy.min_funding_satoshis).unwrap_or(false) {
+		return Err("inbound_channels.max_funding_satoshis must not be less than channel_limits.min_funding_satoshis".to_string());
+	}
+	config.channel_policy.max_total_capacity_satoshis = config.max_total_channel_capacity_sat;
+
+	let limits = &config.user_config.peer_channel_config_limits;
+	if limits.min_dust_limit_satoshis > limits.max_dust_limit_satoshis {
+		return Err("channel_limits.min_dust_limit_satoshis must not be greater than channel_limits.max_dust_limit_satoshis".to_string());
+	}
+	if config.user_config.own_channel_config.minimum_depth > limits.max_minimum_depth {
+		return Err("channels.minimum_depth must not be greater than channel_limits.max_minimum_depth".to_string());
+	}
+	Ok(config)
+}
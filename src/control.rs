@@ -0,0 +1,59 @@
+use crate::commands::*;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::task::JoinHandle;
+
+use rand::{thread_rng, Rng};
+
+use std::fs;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::sync::Arc;
+
+/// Accepts shell commands on a Unix socket at path. Each line a client sends is run as a command
+/// and answered with a single line of JSON of the form {"output": "..."}.
+///
+/// The socket is only accessible by our own user, as it gives full control over our funds.
+pub fn spawn_control_socket(path: &str, handler: Arc<CommandHandler>) -> Result<JoinHandle<()>, std::io::Error> {
+	// Clean up the socket left behind by a previous run, but never anything else.
+	match fs::symlink_metadata(path) {
+		Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+		Ok(_) => return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, "path exists and is not a socket")),
+		Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {},
+		Err(e) => return Err(e),
+	}
+	// Bind in a directory only we can enter and restrict the socket before moving it into place,
+	// so nobody else can connect before its permissions are set.
+	let bind_dir = format!("{}.{:016x}", path, thread_rng().gen::<u64>());
+	fs::DirBuilder::new().mode(0o700).create(&bind_dir)?;
+	let bind_path = format!("{}/socket", bind_dir);
+	let bound = tokio::net::UnixListener::bind(&bind_path).and_then(|listener| {
+		fs::set_permissions(&bind_path, fs::Permissions::from_mode(0o600))?;
+		fs::rename(&bind_path, path)?;
+		Ok(listener)
+	});
+	let _ = fs::remove_file(&bind_path);
+	let _ = fs::remove_dir(&bind_dir);
+	let mut listener = bound?;
+	Ok(tokio::spawn(async move {
+		loop {
+			let stream = match listener.accept().await {
+				Ok((stream, _)) => stream,
+				Err(e) => {
+					println!("WARNING: Failed to accept control socket connection: {}", e);
+					continue;
+				}
+			};
+			let handler = handler.clone();
+			tokio::spawn(async move {
+				let (reader, mut writer) = tokio::io::split(stream);
+				let mut lines = BufReader::new(reader).lines();
+				while let Ok(Some(line)) = lines.next_line().await {
+					let output = handler.run_command(line.trim(), false).await;
+					let mut response = serde_json::to_vec(&serde_json::json!({ "output": output })).unwrap();
+					response.push(b'\n');
+					if writer.write_all(&response).await.is_err() { break; }
+				}
+			});
+		}
+	}))
+}
@@ -0,0 +1,87 @@
+use crate::utils::*;
+
+use lightning::ln::channelmanager::ChannelDetails;
+
+use tokio::sync::broadcast;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How many records a slow subscriber can fall behind by before it starts missing them (and is
+/// sent a "lagged" record saying how many it missed).
+const EVENT_BUFFER: usize = 1024;
+
+struct ChannelState {
+	short_channel_id: Option<u64>,
+	is_live: bool,
+}
+
+/// Publishes a JSON record for everything interesting that happens to the node (channel state
+/// changes, payments and on-chain sweeps) to any number of subscribers. Every record has a "type"
+/// and a unix "timestamp" alongside its own fields.
+pub struct EventStream {
+	sender: broadcast::Sender<String>,
+	/// The last state we reported for each channel, so that we can report what changed.
+	channels: Mutex<HashMap<[u8; 32], ChannelState>>,
+}
+impl EventStream {
+	pub fn new(channels: &[ChannelDetails]) -> Self {
+		let (sender, _) = broadcast::channel(EVENT_BUFFER);
+		Self {
+			sender,
+			channels: Mutex::new(channels.iter().map(|chan| (chan.channel_id, ChannelState {
+				short_channel_id: chan.short_channel_id,
+				is_live: chan.is_live,
+			})).collect()),
+		}
+	}
+
+	pub fn subscribe(&self) -> broadcast::Receiver<String> {
+		self.sender.subscribe()
+	}
+
+	pub fn emit(&self, event_type: &str, mut fields: serde_json::Value) {
+		let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+		fields["type"] = serde_json::json!(event_type);
+		fields["timestamp"] = serde_json::json!(timestamp);
+		// Fails only if nobody is subscribed, which is fine.
+		let _ = self.sender.send(fields.to_string());
+	}
+
+	fn emit_channel(&self, event_type: &str, chan: &ChannelDetails) {
+		self.emit(event_type, serde_json::json!({
+			"channel_id": hex_str(&chan.channel_id[..]),
+			"short_channel_id": chan.short_channel_id,
+			"node_id": hex_str(&chan.remote_network_id.serialize()),
+			"value_sat": chan.channel_value_satoshis,
+		}));
+	}
+
+	/// Compares channels to what we last saw, emitting channel_pending, channel_confirmed,
+	/// channel_live, channel_offline and channel_closed records for anything that changed.
+	pub fn update_channels(&self, channels: &[ChannelDetails]) {
+		let mut known = self.channels.lock().unwrap();
+		for chan in channels {
+			let prev = known.insert(chan.channel_id, ChannelState { short_channel_id: chan.short_channel_id, is_live: chan.is_live });
+			let (prev_short_id, prev_live) = match prev {
+				Some(state) => (state.short_channel_id, state.is_live),
+				None => {
+					self.emit_channel("channel_pending", chan);
+					(None, false)
+				},
+			};
+			if prev_short_id.is_none() && chan.short_channel_id.is_some() {
+				self.emit_channel("channel_confirmed", chan);
+			}
+			if prev_live != chan.is_live {
+				self.emit_channel(if chan.is_live { "channel_live" } else { "channel_offline" }, chan);
+			}
+		}
+		known.retain(|channel_id, _| {
+			if channels.iter().any(|chan| chan.channel_id == *channel_id) { return true; }
+			self.emit("channel_closed", serde_json::json!({ "channel_id": hex_str(&channel_id[..]) }));
+			false
+		});
+	}
+}
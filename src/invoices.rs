@@ -0,0 +1,137 @@
+use crate::utils::*;
+
+use lightning_invoice::{Currency, Fallback, Invoice, InvoiceDescription, ParseOrSemanticError};
+
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::network::constants::Network;
+use bitcoin::util::address::Address;
+
+use time::OffsetDateTime;
+
+use std::str::FromStr;
+use std::time::SystemTime;
+
+pub fn currency_to_network(currency: Currency) -> Network {
+	match currency {
+		Currency::Bitcoin => Network::Bitcoin,
+		Currency::BitcoinTestnet => Network::Testnet,
+		Currency::Regtest => Network::Regtest,
+	}
+}
+
+pub fn network_to_currency(network: Network) -> Currency {
+	match network {
+		Network::Bitcoin => Currency::Bitcoin,
+		Network::Testnet => Currency::BitcoinTestnet,
+		Network::Regtest => Currency::Regtest,
+	}
+}
+
+fn fallback_address(fallback: &Fallback, network: Network) -> Option<Address> {
+	let script = match fallback {
+		Fallback::SegWitProgram { version, program } => Builder::new()
+			.push_int(version.to_u8() as i64)
+			.push_slice(&program[..])
+			.into_script(),
+		Fallback::PubKeyHash(hash) => Builder::new()
+			.push_opcode(opcodes::all::OP_DUP)
+			.push_opcode(opcodes::all::OP_HASH160)
+			.push_slice(&hash[..])
+			.push_opcode(opcodes::all::OP_EQUALVERIFY)
+			.push_opcode(opcodes::all::OP_CHECKSIG)
+			.into_script(),
+		Fallback::ScriptHash(hash) => Builder::new()
+			.push_opcode(opcodes::all::OP_HASH160)
+			.push_slice(&hash[..])
+			.push_opcode(opcodes::all::OP_EQUAL)
+			.into_script(),
+	};
+	Address::from_script(&script, network)
+}
+
+fn format_time(time: &SystemTime) -> String {
+	match time.duration_since(SystemTime::UNIX_EPOCH) {
+		Ok(since_epoch) => OffsetDateTime::from_unix_timestamp(since_epoch.as_secs() as i64).format("%F %T UTC"),
+		Err(_) => "before 1970".to_string(),
+	}
+}
+
+/// Gives a human-readable explanation for why an invoice failed to decode, with some hints for
+/// the common ways people mangle invoices when copy-pasting them.
+pub fn describe_invoice_error(invoice_str: &str, err: &ParseOrSemanticError) -> String {
+	let mut res = match err {
+		ParseOrSemanticError::ParseError(e) => format!("Failed to parse invoice: {}", e),
+		ParseOrSemanticError::SemanticError(e) => format!("Invoice parsed but is invalid: {}", e),
+	};
+	let lower = invoice_str.to_ascii_lowercase();
+	if lower.starts_with("lightning:") {
+		res += " (remove the \"lightning:\" URI prefix)";
+	} else if !lower.starts_with("ln") {
+		res += " (BOLT11 invoices start with \"ln\")";
+	} else if invoice_str != lower && invoice_str != invoice_str.to_ascii_uppercase() {
+		res += " (invoices must not be mixed-case)";
+	} else if invoice_str.trim() != invoice_str {
+		res += " (invoice contains leading or trailing whitespace)";
+	}
+	res
+}
+
+/// Describes every field we understand from a BOLT11 invoice, without checking it against our own
+/// network or trying to pay it.
+pub fn format_decoded_invoice(invoice_str: &str) -> String {
+	let mut res = String::new();
+	let invoice = match Invoice::from_str(invoice_str) {
+		Ok(invoice) => invoice,
+		Err(e) => return describe_invoice_error(invoice_str, &e) + "\n",
+	};
+	let network = currency_to_network(invoice.currency());
+	res += &format!("network: {}\n", network);
+	match invoice.amount_pico_btc() {
+		Some(amt) if amt % 10 == 0 => res += &format!("amount: {} msat\n", amt / 10),
+		Some(amt) => res += &format!("amount: {} pico-BTC (not a whole number of msat!)\n", amt),
+		None => res += "amount: none, payer chooses\n",
+	}
+	let recovered_payee = invoice.recover_payee_pub_key();
+	match invoice.payee_pub_key() {
+		Some(payee) if *payee == recovered_payee => res += &format!("payee: {} (explicit, matches signature)\n", hex_str(&payee.serialize())),
+		Some(payee) => res += &format!("payee: {} (explicit) DOES NOT MATCH {} (recovered from signature)\n", hex_str(&payee.serialize()), hex_str(&recovered_payee.serialize())),
+		None => res += &format!("payee: {} (recovered from signature)\n", hex_str(&recovered_payee.serialize())),
+	}
+	res += &format!("payment_hash: {}\n", hex_str(&invoice.payment_hash()[..]));
+	match invoice.payment_secret() {
+		Some(secret) => res += &format!("payment_secret: {}\n", hex_str(&secret.0)),
+		None => res += "payment_secret: none\n",
+	}
+	match invoice.description() {
+		InvoiceDescription::Direct(desc) => res += &format!("description: {}\n", desc.clone().into_inner()),
+		InvoiceDescription::Hash(hash) => res += &format!("description hash: {}\n", hex_str(&hash.0[..])),
+	}
+	let expiry = invoice.expiry_time();
+	let expires_at = *invoice.timestamp() + expiry;
+	res += &format!("timestamp: {}\n", format_time(invoice.timestamp()));
+	res += &format!("expiry: {} seconds (at {}){}\n", expiry.as_secs(), format_time(&expires_at),
+		if expires_at < SystemTime::now() { ", EXPIRED" } else { "" });
+	res += &format!("min_final_cltv_expiry: {}\n", match invoice.min_final_cltv_expiry() {
+		Some(cltv) => cltv.to_string(),
+		None => "not set (defaults to 9)".to_string(),
+	});
+	for fallback in invoice.fallbacks() {
+		match fallback_address(fallback, network) {
+			Some(addr) => res += &format!("fallback address: {}\n", addr),
+			None => res += &format!("fallback address: unrepresentable {:?}\n", fallback),
+		}
+	}
+	match invoice.features() {
+		Some(features) => res += &format!("features: {:?}\n", features),
+		None => res += "features: none\n",
+	}
+	for (idx, route) in invoice.routes().iter().enumerate() {
+		res += &format!("route hint {}:\n", idx);
+		for hop in route.iter() {
+			res += &format!("  -> {} via {}, base fee {} msat, proportional fee {} millionths, CLTV delta {}\n",
+				hex_str(&hop.pubkey.serialize()), slice_to_be64(&hop.short_channel_id), hop.fee_base_msat, hop.fee_proportional_millionths, hop.cltv_expiry_delta);
+		}
+	}
+	res
+}
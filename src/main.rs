@@ -7,9 +7,62 @@ use utils::*;
 mod chain_monitor;
 use chain_monitor::*;
 
+mod invoices;
+use invoices::*;
+
+mod payments;
+use payments::*;
+
+mod persist;
+use persist::*;
+
+mod replication;
+use replication::*;
+
+mod backup;
+use backup::*;
+
+mod seed;
+use seed::*;
+
+mod archive;
+use archive::*;
+
+mod config;
+use config::*;
+
+mod mainnet;
+use mainnet::*;
+
+mod commands;
+use commands::*;
+
+mod control;
+use control::*;
+
+mod api;
+use api::*;
+
+mod events;
+use events::*;
+
+mod shell;
+use shell::*;
+
+mod net;
+use net::*;
+
+mod reconnect;
+use reconnect::*;
+
+mod peer_filter;
+use peer_filter::*;
+
+mod announce;
+use announce::*;
+
 use lightning_net_tokio::*;
 
-use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::sync::mpsc;
 use tokio::time::Instant;
 
@@ -24,13 +77,12 @@ use time::OffsetDateTime;
 use lightning::chain;
 use lightning::chain::chaininterface;
 use lightning::chain::keysinterface::{KeysInterface, KeysManager, SpendableOutputDescriptor, InMemoryChannelKeys};
-use lightning::ln::{peer_handler, router, channelmanager, channelmonitor, msgs};
+use lightning::ln::{peer_handler, router, channelmanager, channelmonitor};
 use lightning::ln::channelmonitor::ManyChannelMonitor;
 use lightning::ln::channelmanager::{PaymentHash, PaymentPreimage};
 use lightning::util::events::{Event, EventsProvider};
-use lightning::util::logger::{Logger, Record};
+use lightning::util::logger::{Level, Logger, Record};
 use lightning::util::ser::{ReadableArgs, Writeable};
-use lightning::util::config;
 
 use bitcoin::util::{bip32, bip143};
 use bitcoin::blockdata;
@@ -39,22 +91,19 @@ use bitcoin::consensus::encode;
 
 use bitcoin::hashes::Hash;
 use bitcoin::hashes::sha256::Hash as Sha256Hash;
-use bitcoin::hashes::hex::{ToHex, FromHex};
+use bitcoin::hashes::hex::FromHex;
 use bitcoin::hash_types::{BlockHash, Txid};
 
 use std::{env, mem};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::vec::Vec;
 use std::time::{Duration, SystemTime};
-use std::io::{BufWriter, Cursor, Write};
+use std::io::Cursor;
 use std::fs;
 
-const FEE_PROPORTIONAL_MILLIONTHS: u32 = 10;
-const ANNOUNCE_CHANNELS: bool = false;
-
 // TODO: There are several dropped tokio::JoinHandle's in this file where we call tokio::spawn and then
 // drop the result. In the future, this may break things, see https://github.com/tokio-rs/tokio/issues/1830.
 
@@ -67,25 +116,28 @@ fn _check_usize_is_64() {
 struct EventHandler {
 	secp_ctx: Secp256k1<secp256k1::All>,
 	network: constants::Network,
-	file_prefix: String,
+	store: Arc<dyn NodeStore>,
 	rpc_client: Arc<RPCClient>,
-	peer_manager: peer_handler::SimpleArcPeerManager<lightning_net_tokio::SocketDescriptor, ChannelMonitor, ChainInterface, FeeEstimator>,
+	peer_manager: FilteredPeerManager,
 	channel_manager: channelmanager::SimpleArcChannelManager<ChannelMonitor, ChainInterface, FeeEstimator>,
 	monitor: Arc<channelmonitor::SimpleManyChannelMonitor<chain::transaction::OutPoint, InMemoryChannelKeys, Arc<ChainInterface>, Arc<FeeEstimator>>>,
 	router: Arc<router::Router>,
 	broadcaster: Arc<dyn chain::chaininterface::BroadcasterInterface>,
 	txn_to_broadcast: Mutex<HashMap<chain::transaction::OutPoint, blockdata::transaction::Transaction>>,
 	payment_preimages: Arc<Mutex<HashMap<PaymentHash, PaymentPreimage>>>,
+	payments: Arc<PaymentTracker>,
+	backup: Arc<ChannelBackup>,
+	events: Arc<EventStream>,
 }
 impl EventHandler {
-	async fn setup(network: constants::Network, file_prefix: String, rpc_client: Arc<RPCClient>,
-		peer_manager: peer_handler::SimpleArcPeerManager<lightning_net_tokio::SocketDescriptor, ChannelMonitor, ChainInterface, FeeEstimator>,
+	async fn setup(network: constants::Network, store: Arc<dyn NodeStore>, rpc_client: Arc<RPCClient>,
+		peer_manager: FilteredPeerManager,
 		monitor: Arc<channelmonitor::SimpleManyChannelMonitor<chain::transaction::OutPoint, InMemoryChannelKeys, Arc<ChainInterface>, Arc<FeeEstimator>>>,
 		channel_manager: channelmanager::SimpleArcChannelManager<ChannelMonitor, ChainInterface, FeeEstimator>,
 		router: Arc<router::Router>, broadcaster: Arc<dyn chain::chaininterface::BroadcasterInterface>,
-		payment_preimages: Arc<Mutex<HashMap<PaymentHash, PaymentPreimage>>>) -> mpsc::Sender<()>
+		payment_preimages: Arc<Mutex<HashMap<PaymentHash, PaymentPreimage>>>, payments: Arc<PaymentTracker>, backup: Arc<ChannelBackup>, events: Arc<EventStream>) -> mpsc::Sender<()>
 	{
-		let us = Arc::new(Self { secp_ctx: Secp256k1::new(), network, file_prefix, rpc_client, peer_manager, channel_manager, monitor, router, broadcaster, txn_to_broadcast: Mutex::new(HashMap::new()), payment_preimages });
+		let us = Arc::new(Self { secp_ctx: Secp256k1::new(), network, store, rpc_client, peer_manager, channel_manager, monitor, router, broadcaster, txn_to_broadcast: Mutex::new(HashMap::new()), payment_preimages, payments, backup, events });
 		let (mut io_wake, mut io_receiver) = mpsc::channel(2);
 		let (sender, mut receiver) = mpsc::channel(2);
 		let mut self_sender = sender.clone();
@@ -104,16 +156,9 @@ impl EventHandler {
 			loop {
 				io_receiver.recv().await.unwrap();
 
-				let router_filename = format!("{}/router_data", us.file_prefix);
-				let router_tmp_filename = router_filename.clone() + ".tmp";
-
-				{
-					let f = fs::File::create(&router_tmp_filename).unwrap();
-					let mut writer = BufWriter::new(f);
-					us.router.write(&mut writer).unwrap();
-					writer.flush().unwrap();
+				if let Err(e) = us.store.write(&StorageKey::Router, &us.router.encode()) {
+					println!("WARNING: Failed to write router data to disk: {}", e);
 				}
-				fs::rename(&router_tmp_filename, &router_filename).unwrap();
 			}
 		});
 		sender
@@ -126,12 +171,7 @@ impl EventHandler {
 		for event in events {
 			match event {
 				Event::FundingGenerationReady { temporary_channel_id, channel_value_satoshis, output_script, .. } => {
-					let addr = bitcoin_bech32::WitnessProgram::from_scriptpubkey(&output_script[..], match us.network {
-							constants::Network::Bitcoin => bitcoin_bech32::constants::Network::Bitcoin,
-							constants::Network::Testnet => bitcoin_bech32::constants::Network::Testnet,
-							constants::Network::Regtest => bitcoin_bech32::constants::Network::Regtest,
-						}
-					).expect("LN funding tx should always be to a SegWit output").to_address();
+					let addr = bitcoin_bech32::WitnessProgram::from_scriptpubkey(&output_script[..], network_to_bech32(us.network)).expect("LN funding tx should always be to a SegWit output").to_address();
 					let mut self_sender = self_sender.clone();
 					let outputs = format!("{{\"{}\": {}}}", addr, channel_value_satoshis as f64 / 1_000_000_00.0).to_string();
 					if let Ok(tx_hex) = us.rpc_client.make_rpc_call("createrawtransaction", &["[]", &outputs], false).await {
@@ -160,22 +200,49 @@ impl EventHandler {
 					let tx = txn.remove(&funding_txo).unwrap();
 					us.broadcaster.broadcast_transaction(&tx);
 					println!("Broadcast funding tx {}!", tx.txid());
+					us.events.emit("funding_broadcast", serde_json::json!({
+						"funding_txid": tx.txid().to_string(),
+						"funding_output_index": funding_txo.index,
+					}));
 				},
 				Event::PaymentReceived { payment_hash, payment_secret, amt } => {
 					let images = us.payment_preimages.lock().unwrap();
-					if let Some(payment_preimage) = images.get(&payment_hash) {
+					let claimed = if let Some(payment_preimage) = images.get(&payment_hash) {
 						assert!(us.channel_manager.claim_funds(payment_preimage.clone(), &payment_secret, amt));
+						true
 					} else {
 						println!("Received payment but we didn't know the preimage :(");
 						us.channel_manager.fail_htlc_backwards(&payment_hash, &payment_secret);
-					}
+						false
+					};
+					us.events.emit("payment_received", serde_json::json!({
+						"payment_hash": hex_str(&payment_hash.0),
+						"amount_msat": amt,
+						"claimed": claimed,
+					}));
 					let _ = self_sender.try_send(());
 				},
 				Event::PaymentSent { payment_preimage } => {
 					println!("Less money :(, proof: {}", hex_str(&payment_preimage.0));
+					let payment_hash = PaymentHash(Sha256Hash::hash(&payment_preimage.0).into_inner());
+					if us.payments.payment_sent(&payment_hash) {
+						us.events.emit("payment_sent", serde_json::json!({
+							"payment_hash": hex_str(&payment_hash.0),
+							"payment_preimage": hex_str(&payment_preimage.0),
+						}));
+					}
 				},
 				Event::PaymentFailed { payment_hash, rejected_by_dest } => {
 					println!("{} failed id {}!", if rejected_by_dest { "Send" } else { "Route" }, hex_str(&payment_hash.0));
+					let retrying = us.payments.payment_failed(&us.router, &us.channel_manager, &payment_hash, rejected_by_dest);
+					if retrying {
+						let _ = self_sender.try_send(());
+					}
+					us.events.emit("payment_failed", serde_json::json!({
+						"payment_hash": hex_str(&payment_hash.0),
+						"rejected_by_dest": rejected_by_dest,
+						"retrying": retrying,
+					}));
 				},
 				Event::PendingHTLCsForwardable { time_forwardable } => {
 					let us = us.clone();
@@ -189,8 +256,13 @@ impl EventHandler {
 				Event::SpendableOutputs { mut outputs } => {
 					for output in outputs.drain(..) {
 						match output {
-							SpendableOutputDescriptor:: StaticOutput { outpoint, .. } => {
+							SpendableOutputDescriptor:: StaticOutput { outpoint, output } => {
 								println!("Got on-chain output Bitcoin Core should know how to claim at {}:{}", hex_str(&outpoint.txid[..]), outpoint.vout);
+								us.events.emit("output_spendable", serde_json::json!({
+									"outpoint": outpoint.to_string(),
+									"value_sat": output.value,
+									"claimed_by": "wallet",
+								}));
 							},
 							SpendableOutputDescriptor::DynamicOutputP2WSH { outpoint, key, witness_script, to_self_delay, output } => {
 								println!("Got on-chain output ({}:{}) to redeemScript {} spendable with key {} at time {}...", hex_str(&outpoint.txid[..]), outpoint.vout, hex_str(&witness_script[..]), hex_str(&key[..]), to_self_delay);
@@ -215,12 +287,22 @@ impl EventHandler {
 								tx.input[0].witness.insert(0, us.secp_ctx.sign(&sighash, &key).serialize_der()[..].to_vec());
 								println!("Spending it with {}", encode::serialize_hex(&tx));
 								us.broadcaster.broadcast_transaction(&tx);
+								us.events.emit("output_swept", serde_json::json!({
+									"outpoint": outpoint.to_string(),
+									"value_sat": output.value,
+									"sweep_txid": tx.txid().to_string(),
+								}));
 							},
-							SpendableOutputDescriptor::DynamicOutputP2WPKH { outpoint, key, output: _ } => {
+							SpendableOutputDescriptor::DynamicOutputP2WPKH { outpoint, key, output } => {
 								println!("Got on-chain output ({}:{}) we should claim directly with key {}", hex_str(&outpoint.txid[..]), outpoint.vout, hex_str(&key[..]));
 								us.rpc_client.make_rpc_call("importprivkey",
 									&[&("\"".to_string() + &bitcoin::util::key::PrivateKey{ key, compressed: true, network: us.network}.to_wif() + "\""), "\"rust-lightning dynamic output p2wpkh\"", "false"], false).await.unwrap();
 								let _ = us.rpc_client.make_rpc_call("rescanblockchain", &["610000"], false).await;
+								us.events.emit("output_spendable", serde_json::json!({
+									"outpoint": outpoint.to_string(),
+									"value_sat": output.value,
+									"claimed_by": "imported_key",
+								}));
 							},
 						}
 					}
@@ -228,54 +310,99 @@ impl EventHandler {
 			}
 		}
 
-		let manager_filename = format!("{}/manager_data", us.file_prefix);
-		let manager_tmp_filename = manager_filename.clone() + ".tmp";
-		{
-			let f = fs::File::create(&manager_tmp_filename).unwrap();
-			let mut writer = BufWriter::new(f);
-			us.channel_manager.write(&mut writer).unwrap();
-			writer.flush().unwrap();
+		if let Err(e) = us.store.write(&StorageKey::ChannelManager, &us.channel_manager.encode()) {
+			// Keep running so that monitors keep getting updated and our counterparties don't see us
+			// go offline, but retry until the disk comes back.
+			println!("ERROR: Failed to write channel manager to disk, will retry: {}", e);
+			let mut self_sender = self_sender.clone();
+			tokio::spawn(async move {
+				tokio::time::delay_for(Duration::from_secs(1)).await;
+				let _ = self_sender.try_send(());
+			});
 		}
-		fs::rename(&manager_tmp_filename, &manager_filename).unwrap();
+		let channels = us.channel_manager.list_channels();
+		us.backup.update(&channels);
+		us.events.update_channels(&channels);
 	}
 }
 
 struct ChannelMonitor {
 	monitor: Arc<channelmonitor::SimpleManyChannelMonitor<chain::transaction::OutPoint, InMemoryChannelKeys, Arc<ChainInterface>, Arc<FeeEstimator>>>,
-	file_prefix: String,
+	store: Arc<dyn NodeStore>,
+	/// If we have any replicas, every monitor update is queued here and its channel paused (we
+	/// return TemporaryFailure) until it has reached all of them.
+	replicator: Option<Replicator>,
 }
 impl ChannelMonitor {
-	async fn load_from_disk(file_prefix: &String, cur_tip_hash: BlockHash, rpc_client: (&str, &str), broadcaster: Arc<ChainInterface>, feeest: Arc<FeeEstimator>) -> Vec<(chain::transaction::OutPoint, channelmonitor::ChannelMonitor<InMemoryChannelKeys>)> {
+	/// Reads and checks a stored monitor, returning a description of what's wrong with it if we
+	/// can't use it.
+	fn decode_monitor(funding_txo: &chain::transaction::OutPoint, data: &[u8], logger: &Arc<LogPrinter>) -> Result<(BlockHash, channelmonitor::ChannelMonitor<InMemoryChannelKeys>), String> {
+		let serialized = unseal_monitor(data)?;
+		let (last_block_hash, monitor) = <(BlockHash, channelmonitor::ChannelMonitor<InMemoryChannelKeys>)>::read(&mut Cursor::new(serialized), logger.clone())
+			.map_err(|e| format!("failed to deserialize: {:?}", e))?;
+		if monitor.get_funding_txo() != *funding_txo {
+			return Err(format!("contains the monitor for {}:{} instead", monitor.get_funding_txo().txid, monitor.get_funding_txo().index));
+		}
+		Ok((last_block_hash, monitor))
+	}
+
+	/// Loads every monitor in the store, falling back to the .bk copy left behind by an interrupted
+	/// write if the primary is unusable. The .bk copy is the version from before an update which
+	/// never completed (and thus was never acted on), so it is safe to continue from. If any
+	/// monitor can't be recovered, returns a description of each failure instead.
+	async fn load_from_store(store: &Arc<dyn NodeStore>, cur_tip_hash: BlockHash, rpc_client: (&str, &str), broadcaster: Arc<ChainInterface>, feeest: Arc<FeeEstimator>, logger: Arc<LogPrinter>) -> Result<Vec<(chain::transaction::OutPoint, channelmonitor::ChannelMonitor<InMemoryChannelKeys>)>, Vec<String>> {
 		let mut res = Vec::new();
-		for file_option in fs::read_dir(file_prefix).unwrap() {
-			let mut loaded = false;
-			let file = file_option.unwrap();
-			if let Some(filename) = file.file_name().to_str() {
-				if filename.is_ascii() && filename.len() > 65 {
-					if let Ok(txid) = Txid::from_hex(filename.split_at(64).0) {
-						if let Ok(index) = filename.split_at(65).1.split('.').next().unwrap().parse() {
-							if let Ok(contents) = fs::read(&file.path()) {
-								if let Ok((last_block_hash, mut loaded_monitor)) = <(BlockHash, channelmonitor::ChannelMonitor<InMemoryChannelKeys>)>::read(&mut Cursor::new(&contents), Arc::new(LogPrinter{})) {
-									let monitor_data = (&mut loaded_monitor, &*broadcaster, &*feeest);
-									init_sync_chain_monitor(cur_tip_hash.clone(), last_block_hash, rpc_client, monitor_data).await;
-
-									res.push((chain::transaction::OutPoint { txid, index }, loaded_monitor));
-									loaded = true;
-								}
+		let mut failures = Vec::new();
+		for funding_txo in store.list_monitors().expect("Failed to list channel monitors") {
+			let key = StorageKey::Monitor(funding_txo);
+			let primary = match store.read(&key) {
+				Ok(Some(contents)) => Self::decode_monitor(&funding_txo, &contents, &logger),
+				Ok(None) => Err("missing".to_string()),
+				Err(e) => Err(format!("failed to read: {}", e)),
+			};
+			let (last_block_hash, mut loaded_monitor) = match primary {
+				Ok(loaded) => loaded,
+				Err(primary_err) => match store.read_monitor_backup(&funding_txo) {
+					Ok(Some(contents)) => match Self::decode_monitor(&funding_txo, &contents, &logger) {
+						Ok(loaded) => {
+							println!("WARNING: Channel monitor {} was unusable ({}), recovered it from its .bk copy", key.name(), primary_err);
+							if let Err(e) = store.restore_monitor_backup(&funding_txo) {
+								failures.push(format!("{}: {}, and restoring its .bk copy failed: {}", key.name(), primary_err, e));
+								continue;
 							}
-						}
-					}
-				}
-			}
-			if !loaded {
-				println!("WARNING: Failed to read one of the channel monitor storage files! Check perms!");
-			}
+							loaded
+						},
+						Err(bk_err) => {
+							failures.push(format!("{}: {}, and its .bk copy is unusable too: {}", key.name(), primary_err, bk_err));
+							continue;
+						},
+					},
+					Ok(None) => {
+						failures.push(format!("{}: {}, and there is no .bk copy", key.name(), primary_err));
+						continue;
+					},
+					Err(e) => {
+						failures.push(format!("{}: {}, and reading its .bk copy failed: {}", key.name(), primary_err, e));
+						continue;
+					},
+				},
+			};
+			let monitor_data = (&mut loaded_monitor, &*broadcaster, &*feeest);
+			init_sync_chain_monitor(cur_tip_hash.clone(), last_block_hash, rpc_client, monitor_data).await;
+			res.push((funding_txo, loaded_monitor));
 		}
-		res
+		if failures.is_empty() { Ok(res) } else { Err(failures) }
 	}
 
+	/// Loaded monitors are queued for replication too, as a newly added replica (or one which
+	/// missed updates while we were down) would otherwise lack every channel which stays idle.
 	fn load_from_vec(&self, mut monitors: Vec<(chain::transaction::OutPoint, channelmonitor::ChannelMonitor<InMemoryChannelKeys>)>) {
 		for (outpoint, monitor) in monitors.drain(..) {
+			if let Some(replicator) = &self.replicator {
+				let mut data = Vec::new();
+				monitor.write_for_disk(&mut data).expect("Failed to serialize monitor that deserialized");
+				replicator.queue(outpoint, monitor.get_latest_update_id(), seal_monitor(&data));
+			}
 			if let Err(_) = self.monitor.add_monitor(outpoint, monitor) {
 				panic!("Failed to load monitor that deserialized");
 			}
@@ -283,56 +410,19 @@ impl ChannelMonitor {
 	}
 
 	fn write_monitor(&self, monitor: &channelmonitor::ChannelMonitor<InMemoryChannelKeys>) -> Result<(), channelmonitor::ChannelMonitorUpdateErr> {
-		macro_rules! try_fs {
-			($res: expr) => {
-				match $res {
-					Ok(res) => res,
-					Err(_) => return Err(channelmonitor::ChannelMonitorUpdateErr::PermanentFailure),
-				}
-			}
+		let mut data = Vec::new();
+		if monitor.write_for_disk(&mut data).is_err() {
+			return Err(channelmonitor::ChannelMonitorUpdateErr::PermanentFailure);
 		}
-		// Do a crazy dance with lots of fsync()s to be overly cautious here...
-		// We never want to end up in a state where we've lost the old data, or end up using the
-		// old data on power loss after we've returned
-		// Note that this actually *isn't* enough (at least on Linux)! We need to fsync an fd with
-		// the containing dir, but Rust doesn't let us do that directly, sadly. TODO: Fix this with
-		// the libc crate!
+		let data = seal_monitor(&data);
 		let funding_txo = monitor.get_funding_txo();
-		let filename = format!("{}/{}_{}", self.file_prefix, funding_txo.txid.to_hex(), funding_txo.index);
-		let tmp_filename = filename.clone() + ".tmp";
-
-		{
-			let mut f = try_fs!(fs::File::create(&tmp_filename));
-			try_fs!(monitor.write_for_disk(&mut f));
-			try_fs!(f.sync_all());
+		if let Err(e) = self.store.write(&StorageKey::Monitor(funding_txo), &data) {
+			println!("ERROR: Failed to write channel monitor: {}", e);
+			return Err(channelmonitor::ChannelMonitorUpdateErr::PermanentFailure);
 		}
-		// We don't need to create a backup if didn't already have the file, but in any other case
-		// try to create the backup and expect failure on fs::copy() if eg there's a perms issue.
-		let need_bk = match fs::metadata(&filename) {
-			Ok(data) => {
-				if !data.is_file() { return Err(channelmonitor::ChannelMonitorUpdateErr::PermanentFailure); }
-				true
-			},
-			Err(e) => match e.kind() {
-				std::io::ErrorKind::NotFound => false,
-				_ => true,
-			}
-		};
-		let bk_filename = filename.clone() + ".bk";
-		if need_bk {
-			try_fs!(fs::copy(&filename, &bk_filename));
-			{
-				let f = try_fs!(fs::File::open(&bk_filename));
-				try_fs!(f.sync_all());
-			}
-		}
-		try_fs!(fs::rename(&tmp_filename, &filename));
-		{
-			let f = try_fs!(fs::File::open(&filename));
-			try_fs!(f.sync_all());
-		}
-		if need_bk {
-			try_fs!(fs::remove_file(&bk_filename));
+		if let Some(replicator) = &self.replicator {
+			replicator.queue(funding_txo, monitor.get_latest_update_id(), data);
+			return Err(channelmonitor::ChannelMonitorUpdateErr::TemporaryFailure);
 		}
 		Ok(())
 	}
@@ -357,11 +447,15 @@ impl channelmonitor::ManyChannelMonitor<InMemoryChannelKeys> for ChannelMonitor
 	}
 }
 
-struct LogPrinter {}
+struct LogPrinter {
+	level: Level,
+	log_gossip: bool,
+}
 impl Logger for LogPrinter {
 	fn log(&self, record: &Record) {
+		if record.level > self.level { return; }
 		let log = record.args.to_string();
-		if !log.contains("Received message of type 258") && !log.contains("Received message of type 256") && !log.contains("Received message of type 257") {
+		if self.log_gossip || (!log.contains("Received message of type 258") && !log.contains("Received message of type 256") && !log.contains("Received message of type 257")) {
 			eprintln!("{} {:<5} [{}:{}] {}", OffsetDateTime::now_utc().format("%F %T"), record.level.to_string(), record.module_path, record.line, log);
 		}
 	}
@@ -369,12 +463,100 @@ impl Logger for LogPrinter {
 
 #[tokio::main]
 async fn main() {
-	println!("USAGE: rust-lightning-jsonrpc user:pass@rpc_host:port storage_directory_path [port]");
-	if env::args().len() < 3 { return; }
+	println!("USAGE: rust-lightning-jsonrpc [user:pass@rpc_host:port] storage_directory_path [port]");
+	println!("storage_directory_path may be prefixed with sqlite: to store everything in a single SQLite database in it");
+	println!("Settings are read from config.toml in storage_directory_path, see config.example.toml. The RPC URL and port override it.");
+	println!("Options: --config=FILE to read settings from FILE instead");
+	println!("         --set=section.key=value (may be repeated) to override a setting from the config file");
+	println!("         --monitor-replica=DIR or --monitor-replica=KEYFILE@host:port (may be repeated) to synchronously replicate channel monitors");
+	println!("         --seed-passphrase=prompt|env:VAR|fd:N to encrypt a newly-created key_seed and say where to read the passphrase from");
+	println!("         --show-mnemonic to print a BIP39 mnemonic backup of a newly-created key_seed, or --restore-mnemonic to create key_seed from one");
+	println!("         --daemon to not read commands from stdin, only from the control socket");
+	println!("         --control-socket=PATH to accept commands on a Unix socket at PATH (default with --daemon: storage_directory_path/control.sock)");
+	println!("         --enable-mainnet to allow running on mainnet, which also requires encrypted key_seed, a monitor replica and a channel capacity cap");
+	println!("         --recover-from-backup=FILE to start a fresh node from a static channel backup and ask its peers to force-close");
+	let (opts, args): (Vec<String>, Vec<String>) = env::args().partition(|arg| arg.starts_with("--"));
+	if args.len() < 2 { return; }
 
 	lightning_invoice::check_platform();
 
-	let rpc_path = env::args().skip(1).next().unwrap();
+	let mut replicas = Vec::new();
+	let mut recover_from_backup = None;
+	let mut seed_passphrase = None;
+	let mut show_mnemonic = false;
+	let mut restore_mnemonic = false;
+	let mut config_path = None;
+	let mut config_overrides = Vec::new();
+	let mut enable_mainnet = false;
+	let mut daemon = false;
+	let mut control_socket = None;
+	for opt in opts.iter() {
+		if opt == "--enable-mainnet" {
+			enable_mainnet = true;
+		} else if opt == "--daemon" {
+			daemon = true;
+		} else if opt.starts_with("--control-socket=") {
+			control_socket = Some(opt["--control-socket=".len()..].to_string());
+		} else if opt.starts_with("--config=") {
+			config_path = Some(opt["--config=".len()..].to_string());
+		} else if opt.starts_with("--set=") {
+			config_overrides.push(opt["--set=".len()..].to_string());
+		} else if opt == "--show-mnemonic" {
+			show_mnemonic = true;
+		} else if opt == "--restore-mnemonic" {
+			restore_mnemonic = true;
+		} else if opt.starts_with("--seed-passphrase=") {
+			match PassphraseSource::parse(&opt["--seed-passphrase=".len()..]) {
+				Ok(source) => seed_passphrase = Some(source),
+				Err(e) => {
+					println!("{}", e);
+					return;
+				}
+			}
+		} else if opt.starts_with("--recover-from-backup=") {
+			recover_from_backup = Some(opt["--recover-from-backup=".len()..].to_string());
+		} else if opt.starts_with("--monitor-replica=") {
+			match parse_replica(&opt["--monitor-replica=".len()..]) {
+				Ok(replica) => replicas.push(replica),
+				Err(e) => {
+					println!("{}", e);
+					return;
+				}
+			}
+		} else {
+			println!("Unknown option {}", opt);
+			return;
+		}
+	}
+
+	// With a single positional argument it is the storage path, and the RPC URL comes from config.
+	let data_path = args[if args.len() == 2 { 1 } else { 2 }].clone();
+	let mut node_config = match load_config(config_path.or_else(|| default_config_path(&data_path)).as_ref().map(|p| &p[..]), &config_overrides) {
+		Ok(node_config) => node_config,
+		Err(e) => {
+			println!("Invalid configuration: {}", e);
+			return;
+		}
+	};
+	if args.len() > 2 {
+		node_config.rpc = Some(args[1].clone());
+	}
+	match args.get(3).map(|p| p.parse()) {
+		Some(Ok(port)) => for addr in node_config.listen.iter_mut() { addr.set_port(port); },
+		Some(Err(_)) => {
+			println!("Bad port {}", args[3]);
+			return;
+		},
+		None => {},
+	}
+
+	let rpc_path = match node_config.rpc {
+		Some(ref rpc) => rpc.clone(),
+		None => {
+			println!("No bitcoind RPC URL given, either pass one or set bitcoind.rpc in the config file");
+			return;
+		}
+	};
 	let rpc_path_parts: Vec<&str> = rpc_path.split('@').collect();
 	if rpc_path_parts.len() != 2 {
 		println!("Bad RPC URL provided");
@@ -388,51 +570,159 @@ async fn main() {
 	let fee_estimator = Arc::new(FeeEstimator::new());
 
 	println!("Checking validity of RPC URL to bitcoind...");
-	if let Ok(v) = rpc_client.make_rpc_call("getblockchaininfo", &[], false).await {
+	let chain_info = if let Ok(v) = rpc_client.make_rpc_call("getblockchaininfo", &[], false).await {
 		assert!(v["verificationprogress"].as_f64().unwrap() > 0.99);
 		assert!(
 			v["bip9_softforks"]["segwit"]["status"].as_str() == Some("active") ||
 			v["softforks"]["segwit"]["type"].as_str() == Some("buried"));
-		match v["chain"].as_str().unwrap() {
-			"main" => network = constants::Network::Bitcoin,
-			"test" => network = constants::Network::Testnet,
-			"regtest" => network = constants::Network::Regtest,
-			_ => panic!("Unknown network type"),
+		match chain_to_network(v["chain"].as_str().unwrap()) {
+			Ok(chain_network) => network = chain_network,
+			Err(e) => {
+				println!("{}", e);
+				return;
+			}
 		}
-	} else { panic!("Failed to connect to RPC"); }
+		v
+	} else { panic!("Failed to connect to RPC"); };
 	println!("Success! Starting up...");
 
 	if network == constants::Network::Bitcoin {
-		panic!("LOL, you're insane");
-	}
-
-	let data_path = env::args().skip(2).next().unwrap();
-	if !fs::metadata(&data_path).unwrap().is_dir() {
-		println!("Need storage_directory_path to exist and be a directory (or symlink to one)");
-		return;
+		if !enable_mainnet {
+			println!("bitcoind is on mainnet, refusing to start without --enable-mainnet");
+			return;
+		}
+		let problems = mainnet_safety_problems(&node_config, &data_path, replicas.len(), &chain_info);
+		if !problems.is_empty() {
+			println!("Refusing to start on mainnet:");
+			for problem in problems {
+				println!("  {}", problem);
+			}
+			return;
+		}
+		if !confirm_mainnet(&node_config) {
+			println!("Not confirmed, exiting");
+			return;
+		}
 	}
-	let _ = fs::create_dir(data_path.clone() + "/monitors"); // If it already exists, ignore, hopefully perms are ok
 
-	let port: u16 = match env::args().skip(3).next().map(|p| p.parse()) {
-		Some(Ok(p)) => p,
-		Some(Err(e)) => panic!(e),
-		None => 9735,
+	let store = match open_store(&data_path) {
+		Ok(store) => store,
+		Err(e) => {
+			println!("Failed to open storage at {}: {}", data_path, e);
+			return;
+		}
 	};
 
-	let logger = Arc::new(LogPrinter {});
+	let recovery_channels = if let Some(backup_path) = recover_from_backup {
+		match store.read(&StorageKey::ChannelManager) {
+			Ok(None) => {},
+			_ => {
+				println!("Refusing to recover from a channel backup into a storage directory which already has channel state");
+				return;
+			}
+		}
+		if let Ok(None) = store.read(&StorageKey::KeySeed) {
+			println!("Recovering from a channel backup requires the original key_seed, copy it into the storage directory first");
+			return;
+		}
+		match fs::read(&backup_path).map_err(|e| e.to_string()).and_then(|data| parse_backup(&data)) {
+			Ok(channels) => channels,
+			Err(e) => {
+				println!("Failed to read channel backup {}: {}", backup_path, e);
+				return;
+			}
+		}
+	} else { Vec::new() };
 
-	let our_node_seed = if let Ok(seed) = fs::read(data_path.clone() + "/key_seed") {
-		assert_eq!(seed.len(), 32);
-		let mut key = [0; 32];
-		key.copy_from_slice(&seed);
-		key
-	} else {
-		let mut key = [0; 32];
-		thread_rng().fill_bytes(&mut key);
-		let mut f = fs::File::create(data_path.clone() + "/key_seed").unwrap();
-		f.write_all(&key).expect("Failed to write seed to disk");
-		f.sync_all().expect("Failed to sync seed to disk");
-		key
+	let logger = Arc::new(LogPrinter { level: node_config.log_level, log_gossip: node_config.log_gossip });
+
+	if restore_mnemonic {
+		if let Ok(None) = store.read(&StorageKey::KeySeed) {} else {
+			println!("Refusing to restore a mnemonic into a storage directory which already has a key_seed");
+			return;
+		}
+	}
+	let our_node_seed = match store.read(&StorageKey::KeySeed) {
+		Ok(Some(data)) => match load_seed(&data, seed_passphrase.as_ref().unwrap_or(&PassphraseSource::Prompt)) {
+			Ok(seed) => {
+				if network == constants::Network::Bitcoin && !is_encrypted(&data) {
+					let passphrase = match seed_passphrase {
+						Some(PassphraseSource::Prompt) => prompt_new_passphrase(),
+						Some(ref source) => source.read("key_seed passphrase: "),
+						None => {
+							println!("Refusing to use an unencrypted key_seed on mainnet, pass --seed-passphrase to encrypt it");
+							return;
+						}
+					};
+					match passphrase {
+						Ok(ref passphrase) if !passphrase.is_empty() => {
+							if let Err(e) = store.write(&StorageKey::KeySeed, &encrypt_seed(&seed, passphrase)) {
+								println!("Failed to write encrypted key_seed: {}", e);
+								return;
+							}
+							println!("Encrypted key_seed");
+						},
+						Ok(_) => {
+							println!("key_seed must be encrypted on mainnet");
+							return;
+						},
+						Err(e) => {
+							println!("{}", e);
+							return;
+						}
+					}
+				}
+				seed
+			},
+			Err(e) => {
+				println!("Failed to load key_seed: {}", e);
+				return;
+			}
+		},
+		Ok(None) => {
+			let key = if restore_mnemonic {
+				match PassphraseSource::Prompt.read("BIP39 mnemonic: ").and_then(|words| mnemonic_to_seed(&words)) {
+					Ok(key) => key,
+					Err(e) => {
+						println!("{}", e);
+						return;
+					}
+				}
+			} else {
+				let mut key = [0; 32];
+				thread_rng().fill_bytes(&mut key);
+				key
+			};
+			let passphrase = match seed_passphrase {
+				Some(PassphraseSource::Prompt) => prompt_new_passphrase(),
+				Some(ref source) => source.read("key_seed passphrase: "),
+				None => Ok(String::new()),
+			};
+			let data = match passphrase {
+				Ok(ref passphrase) if passphrase.is_empty() && network == constants::Network::Bitcoin => {
+					println!("Refusing to create an unencrypted key_seed on mainnet, pass --seed-passphrase");
+					return;
+				},
+				Ok(ref passphrase) if passphrase.is_empty() => key.to_vec(),
+				Ok(passphrase) => encrypt_seed(&key, &passphrase),
+				Err(e) => {
+					println!("{}", e);
+					return;
+				}
+			};
+			if let Err(e) = store.write(&StorageKey::KeySeed, &data) {
+				println!("Failed to write seed to disk: {}", e);
+				return;
+			}
+			if show_mnemonic {
+				println!("Write down your node's seed mnemonic and store it somewhere safe: {}", seed_to_mnemonic(&key));
+			}
+			key
+		},
+		Err(e) => {
+			println!("Failed to read key_seed: {}", e);
+			return;
+		}
 	};
 	let cur = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
 	let keys = Arc::new(KeysManager::new(&our_node_seed, network, logger.clone(), cur.as_secs(), cur.subsec_nanos()));
@@ -456,25 +746,51 @@ async fn main() {
 	let starting_blockhash = BlockHash::from_hex(starting_chaininfo["bestblockhash"].as_str().unwrap()).unwrap();
 	let starting_blockheight: usize = starting_chaininfo["blocks"].as_u64().unwrap().try_into().unwrap();
 
-	let mut monitors_loaded = ChannelMonitor::load_from_disk(&(data_path.clone() + "/monitors"), starting_blockhash.clone(), (rpc_path_parts[0], rpc_path_parts[1]), chain_monitor.clone(), fee_estimator.clone()).await;
+	let mut monitors_loaded = match ChannelMonitor::load_from_store(&store, starting_blockhash.clone(), (rpc_path_parts[0], rpc_path_parts[1]), chain_monitor.clone(), fee_estimator.clone(), logger.clone()).await {
+		Ok(monitors) => monitors,
+		Err(failures) => {
+			println!("ERROR: Refusing to start as the following channel monitors could not be loaded or recovered:");
+			for failure in failures {
+				println!("  {}", failure);
+			}
+			println!("Running without them could lose the funds in those channels. Restore them from a replica, or start a fresh node with --recover-from-backup.");
+			return;
+		}
+	};
+	let (replicator, monitors_replicated) = if replicas.is_empty() { (None, None) } else {
+		let (replicator, replicated) = Replicator::new(replicas);
+		(Some(replicator), Some(replicated))
+	};
 	let monitor = Arc::new(ChannelMonitor {
 		monitor: Arc::new(channelmonitor::SimpleManyChannelMonitor::new(chain_monitor.clone(), chain_monitor.clone(), logger.clone(), fee_estimator.clone())),
-		file_prefix: data_path.clone() + "/monitors",
+		store: store.clone(),
+		replicator,
 	});
 	block_notifier.register_listener(Arc::clone(&(monitor.monitor.clone() as Arc<dyn chaininterface::ChainListener>)));
 
-	let mut config: config::UserConfig = Default::default();
-	config.channel_options.fee_proportional_millionths = FEE_PROPORTIONAL_MILLIONTHS;
-	config.channel_options.announced_channel = ANNOUNCE_CHANNELS;
-	config.own_channel_config.minimum_depth = 1;
+	match read_fee_policy(&*store) {
+		Ok(Some(fee)) if node_config.fee_overridden => {
+			println!("Ignoring the forwarding fee of {} millionths set with setfees, --set=fees.proportional_millionths takes precedence", fee);
+		},
+		Ok(Some(fee)) => {
+			println!("Using the forwarding fee of {} millionths set with setfees (stored in fee_policy) instead of fees.proportional_millionths", fee);
+			node_config.user_config.channel_options.fee_proportional_millionths = fee;
+		},
+		Ok(None) => {},
+		Err(e) => {
+			println!("{}", e);
+			return;
+		}
+	}
+	let config = node_config.user_config.clone();
 
-	let channel_manager = if let Ok(mut f) = fs::File::open(data_path.clone() + "/manager_data") {
+	let channel_manager = if let Some(manager_data) = store.read(&StorageKey::ChannelManager).expect("Failed to read channel manager") {
 		let (last_block_hash, manager) = {
 			let mut monitors_refs = HashMap::new();
 			for (outpoint, monitor) in monitors_loaded.iter_mut() {
 				monitors_refs.insert(*outpoint, monitor);
 			}
-			<(BlockHash, channelmanager::SimpleArcChannelManager<ChannelMonitor, ChainInterface, FeeEstimator>)>::read(&mut f, channelmanager::ChannelManagerReadArgs {
+			match <(BlockHash, channelmanager::SimpleArcChannelManager<ChannelMonitor, ChainInterface, FeeEstimator>)>::read(&mut Cursor::new(&manager_data), channelmanager::ChannelManagerReadArgs {
 				keys_manager: keys.clone(),
 				fee_estimator: fee_estimator.clone(),
 				monitor: monitor.clone(),
@@ -483,7 +799,20 @@ async fn main() {
 				logger: logger.clone(),
 				default_config: config,
 				channel_monitors: &mut monitors_refs,
-			}).expect("Failed to deserialize channel manager")
+			}) {
+				Ok(res) => res,
+				Err(e) => {
+					println!("ERROR: Failed to deserialize channel manager: {:?}", e);
+					if let Ok(Some(backup)) = store.read(&StorageKey::ChannelBackup) {
+						if let Ok(channels) = parse_backup(&backup) {
+							for chan in channels.iter().filter(|chan| !monitors_refs.contains_key(&chan.funding_txo)) {
+								println!("  No channel monitor found for channel {} (funding {}:{})", hex_str(&chan.channel_id), chan.funding_txo.txid, chan.funding_txo.index);
+							}
+						}
+					}
+					return;
+				}
+			}
 		};
 		monitor.load_from_vec(monitors_loaded);
 		if last_block_hash != starting_blockhash {
@@ -499,8 +828,8 @@ async fn main() {
 	block_notifier.register_listener(Arc::clone(&(channel_manager.clone() as Arc<dyn chaininterface::ChainListener>)));
 
 
-	let router = if let Ok(mut f) = fs::File::open(data_path.clone() + "/router_data") {
-		Arc::new(router::Router::read(&mut f, router::RouterReadArgs {
+	let router = if let Ok(Some(router_data)) = store.read(&StorageKey::Router) {
+		Arc::new(router::Router::read(&mut Cursor::new(&router_data), router::RouterReadArgs {
 			chain_monitor: chain_monitor.clone(),
 			logger: logger.clone()
 		}).expect("Failed to deserialize Router"))
@@ -510,31 +839,91 @@ async fn main() {
 
 	let mut ephemeral_data = [0; 32];
 	rand::thread_rng().fill_bytes(&mut ephemeral_data);
+	let peer_policy = Arc::new(node_config.peer_policy);
+	let channel_policy = node_config.channel_policy;
+	let (recovery_point_sender, mut recovery_points) = mpsc::unbounded_channel();
+	let recovery_point_sender = if recovery_channels.is_empty() { None } else { Some(recovery_point_sender) };
 	let peer_manager = Arc::new(peer_handler::PeerManager::new(peer_handler::MessageHandler {
-		chan_handler: channel_manager.clone(),
+		chan_handler: Arc::new(PeerFilter::new(channel_manager.clone(), peer_policy.clone(), channel_policy, recovery_point_sender)),
 		route_handler: router.clone(),
 	}, keys.get_node_secret(), &ephemeral_data, logger.clone()));
 
 	let payment_preimages = Arc::new(Mutex::new(HashMap::new()));
-	let mut event_notify = EventHandler::setup(network, data_path, rpc_client.clone(), peer_manager.clone(), monitor.monitor.clone(), channel_manager.clone(), router.clone(), chain_monitor.clone(), payment_preimages.clone()).await;
+	let payments = Arc::new(PaymentTracker::new(node_config.max_payment_paths, node_config.max_payment_attempts, node_config.max_payment_fee_base_msat, node_config.max_payment_fee_proportional_millionths));
+	let address_book = Arc::new(PeerAddressBook::new(store.clone()));
+	let channel_backup = Arc::new(ChannelBackup::new(store.clone(), address_book.clone(), PublicKey::from_secret_key(&secp_ctx, &keys.get_node_secret()), (cur.as_secs(), cur.subsec_nanos()), &channel_manager.list_channels()));
+	let events = Arc::new(EventStream::new(&channel_manager.list_channels()));
+	let mut event_notify = EventHandler::setup(network, store.clone(), rpc_client.clone(), peer_manager.clone(), monitor.monitor.clone(), channel_manager.clone(), router.clone(), chain_monitor.clone(), payment_preimages.clone(), payments.clone(), channel_backup.clone(), events.clone()).await;
 
-	println!("Initial setup complete, binding port and running!");
+	if !recovery_channels.is_empty() {
+		println!("Recovering {} channels from backup, claim keys will be imported into bitcoind as each peer reconnects...", recovery_channels.len());
+		// We don't know about any of these channels, so when the peer sends us a
+		// channel_reestablish we'll respond with an error, causing them to force-close.
+		for chan in recovery_channels.iter() {
+			if chan.keys_start_time.is_none() {
+				println!("WARNING: Channel {} pre-dates our channel backups, its funds must be claimed manually once {} closes it", hex_str(&chan.channel_id), hex_str(&chan.peer_node_id.serialize()));
+			}
+			println!("Asking {} to force-close channel {}", hex_str(&chan.peer_node_id.serialize()), hex_str(&chan.channel_id));
+			for addr in chan.peer_addresses.iter() {
+				match connect_outbound(peer_manager.clone(), event_notify.clone(), chan.peer_node_id, addr, node_config.proxy).await {
+					Ok(handle) => {
+						join_handles.push(handle);
+						break;
+					},
+					Err(e) => println!("Failed to connect to {}: {}", addr, e),
+				}
+			}
+		}
 
-	let mut listener = tokio::net::TcpListener::bind(("::".parse::<std::net::Ipv6Addr>().unwrap(), port)).await.unwrap();
+		let recovery_rpc_client = rpc_client.clone();
+		let recovery_logger = logger.clone();
+		join_handles.push(tokio::spawn(async move {
+			let mut imported = HashSet::new();
+			while let Some((channel_id, per_commitment_point)) = recovery_points.recv().await {
+				let chan = match recovery_channels.iter().find(|chan| chan.channel_id == channel_id) {
+					Some(chan) => chan,
+					None => continue,
+				};
+				if chan.keys_start_time.is_none() || !imported.insert((channel_id, per_commitment_point)) { continue; }
+				import_recovery_keys(&recovery_rpc_client, &our_node_seed, network, recovery_logger.clone(), chan, &per_commitment_point).await;
+				println!("Imported claim keys for channel {} into bitcoind", hex_str(&channel_id));
+			}
+		}));
+	}
 
-	let peer_manager_listener = peer_manager.clone();
-	let event_listener = event_notify.clone();
-	join_handles.push(tokio::spawn(async move {
-		loop {
-			let sock = listener.accept().await.unwrap().0;
-			println!("Got new inbound connection, waiting on them to start handshake...");
-			let peer_manager_listener = peer_manager_listener.clone();
-			let event_listener = event_listener.clone();
-			tokio::spawn(async move {
-				setup_inbound(peer_manager_listener, event_listener, sock).await;
-			});
-		}
-	}));
+	println!("Initial setup complete, binding ports and running!");
+
+	let inbound_limiter = Arc::new(InboundLimiter::new(peer_policy.clone()));
+	for listen_addr in node_config.listen.iter() {
+		let mut listener = match tokio::net::TcpListener::bind(listen_addr).await {
+			Ok(listener) => listener,
+			Err(e) => {
+				println!("Failed to listen on {}: {}", listen_addr, e);
+				return;
+			}
+		};
+
+		let peer_manager_listener = peer_manager.clone();
+		let event_listener = event_notify.clone();
+		let inbound_limiter = inbound_limiter.clone();
+		join_handles.push(tokio::spawn(async move {
+			loop {
+				let (sock, addr) = listener.accept().await.unwrap();
+				if let Err(e) = inbound_limiter.accept(addr.ip()) {
+					println!("Rejected inbound connection from {}: {}", addr, e);
+					continue;
+				}
+				println!("Got new inbound connection, waiting on them to start handshake...");
+				let peer_manager_listener = peer_manager_listener.clone();
+				let event_listener = event_listener.clone();
+				let inbound_limiter = inbound_limiter.clone();
+				tokio::spawn(async move {
+					setup_inbound(peer_manager_listener, event_listener, sock).await;
+					inbound_limiter.connection_closed();
+				});
+			}
+		}));
+	}
 
 	join_handles.push(tokio::spawn(
 		rebroadcast_and_update_fees(fee_estimator, chain_monitor, rpc_client.clone())
@@ -544,281 +933,119 @@ async fn main() {
 		spawn_chain_monitor(starting_blockhash, (rpc_path_parts[0], rpc_path_parts[1]), block_notifier, event_notify.clone(), network == constants::Network::Bitcoin)
 	));
 
+	if let Some(mut replicated) = monitors_replicated {
+		let monitor_replication = monitor.clone();
+		let chan_manager_replication = channel_manager.clone();
+		let mut event_replication = event_notify.clone();
+		join_handles.push(tokio::spawn(async move {
+			while let Some(()) = replicated.recv().await {
+				// Restoring only up to the replicated update_id leaves a channel paused if it was
+				// updated again after the version we replicated was queued.
+				for (funding_txo, update_id) in monitor_replication.replicator.as_ref().unwrap().take_replicated() {
+					chan_manager_replication.channel_monitor_updated(&funding_txo, update_id);
+				}
+				let _ = event_replication.try_send(());
+			}
+		}));
+	}
+
+	let monitor_archiver = MonitorArchiver::new(store.clone());
+	let monitor_archive = monitor.clone();
+	let chan_manager_archive = channel_manager.clone();
+	let rpc_client_archive = rpc_client.clone();
+	join_handles.push(tokio::spawn(async move {
+		let mut intvl = tokio::time::interval(Duration::from_secs(60 * 60));
+		loop {
+			intvl.tick().await;
+			let open_channel_ids: HashSet<[u8; 32]> = chan_manager_archive.list_channels().iter().map(|chan| chan.channel_id).collect();
+			monitor_archiver.check(&rpc_client_archive, &monitor_archive, &open_channel_ids).await;
+		}
+	}));
+
+	join_handles.push(spawn_reconnector(peer_manager.clone(), channel_manager.clone(), router.clone(), address_book.clone(), event_notify.clone(), node_config.proxy));
+
+	if node_config.announcement.auto {
+		join_handles.push(spawn_announcer(channel_manager.clone(), router.clone(), node_config.announcement.clone(), event_notify.clone()));
+	}
+
 	let peer_manager_timer = peer_manager.clone();
 	let chan_manager_timer = channel_manager.clone();
+	let events_timer = events.clone();
 	join_handles.push(tokio::spawn(async move {
 		let mut intvl = tokio::time::interval(Duration::from_secs(60));
 		loop {
 			intvl.tick().await;
 			peer_manager_timer.timer_tick_occured();
 			chan_manager_timer.timer_chan_freshness_every_min();
+			// Peers disconnecting don't generate events, so catch channels going offline here.
+			events_timer.update_channels(&chan_manager_timer.list_channels());
 		}
 	}));
 
-	println!("Bound on port {}! Our node_id: {}", port, hex_str(&PublicKey::from_secret_key(&secp_ctx, &keys.get_node_secret()).serialize()));
-	println!("Started interactive shell! Commands:");
-	println!("'a ip:port alias' Announce our node with the given ip:port as listening and the given alias");
-	println!("'c pubkey@host:port' Connect to given host+port, with given pubkey for auth");
-	println!("'n pubkey value push_value' Create a channel with the given connected node (by pubkey), value in satoshis, and push the given msat value");
-	println!("'k channel_id' Close a channel with the given id");
-	println!("'f channel_id' Force close the given channel");
-	println!("'f all' Force close all channels, closing to chain");
-	println!("'l p' List the node_ids of all connected peers");
-	println!("'l c' List details about all channels");
-	println!("'s invoice [amt]' Send payment to an invoice, optionally with amount as whole msat if its not in the invoice");
-	println!("'p amt' Gets a new invoice for receiving funds for the given amt in msat");
-	print!("> "); std::io::stdout().flush().unwrap();
-	let mut lines = BufReader::new(tokio::io::stdin()).lines();
-	while let Ok(Some(line)) = lines.next_line().await {
-		macro_rules! fail_return {
-			() => {
-				print!("> "); std::io::stdout().flush().unwrap();
-				continue;
+	let our_node_id = hex_str(&PublicKey::from_secret_key(&secp_ctx, &keys.get_node_secret()).serialize());
+	if node_config.listen.is_empty() {
+		println!("Not listening for inbound connections! Our node_id: {}", our_node_id);
+	} else {
+		let listen_addrs: Vec<String> = node_config.listen.iter().map(|addr| addr.to_string()).collect();
+		println!("Listening on {}! Our node_id: {}", listen_addrs.join(", "), our_node_id);
+	}
+	let command_handler = Arc::new(CommandHandler {
+		network,
+		secp_ctx: Secp256k1::new(),
+		keys: keys.clone(),
+		our_node_seed,
+		store: store.clone(),
+		peer_manager: peer_manager.clone(),
+		channel_manager: channel_manager.clone(),
+		router: router.clone(),
+		payments: payments.clone(),
+		payment_preimages: payment_preimages.clone(),
+		channel_backup: channel_backup.clone(),
+		address_book: address_book.clone(),
+		event_notify: event_notify.clone(),
+		max_total_channel_capacity_sat: node_config.max_total_channel_capacity_sat,
+		proxy: node_config.proxy,
+		announcement: node_config.announcement.clone(),
+	});
+
+	if let Some(addr) = node_config.api_listen {
+		let server = api_credentials(&node_config.api_auth, &data_path)
+			.and_then(|auth| spawn_api_server(addr, &auth, command_handler.clone(), events.clone()));
+		match server {
+			Ok(handle) => {
+				join_handles.push(handle);
+				println!("Serving the JSON-RPC API on {}", addr);
+			},
+			Err(e) => {
+				println!("{}", e);
+				return;
 			}
 		}
-		if line.len() > 2 && line.as_bytes()[1] == ' ' as u8 {
-			match line.as_bytes()[0] {
-				0x61 => { // 'a'
-					let mut iter = line[2..].splitn(2, ' ');
-					if let Some(hostport) = iter.next() {
-						if let Some(alias) = iter.next() {
-							if let Ok(sockaddr) = hostport.parse::<std::net::SocketAddr>() {
-								if alias.len() <= 32 {
-									let mut aliasbytes = [0u8; 32];
-									aliasbytes[..alias.len()].copy_from_slice(alias.as_bytes());
-									let addr = match sockaddr.ip() {
-										std::net::IpAddr::V4(v4addr) => msgs::NetAddress::IPv4 {
-											addr: v4addr.octets(),
-											port: sockaddr.port()
-										},
-										std::net::IpAddr::V6(v6addr) => msgs::NetAddress::IPv6 {
-											addr: v6addr.octets(),
-											port: sockaddr.port()
-										},
-									};
-									channel_manager.broadcast_node_announcement([0, 0, 0], aliasbytes, vec![addr]);
-									let _ = event_notify.try_send(());
-								} else { println!("alias must be no longer than 32 bytes"); }
-							} else { println!("Failed to map {} into a socket address", hostport); }
-						} else { println!("Invalid or no alias provided"); }
-					} else { println!("No host:port provided"); }
-				},
-				0x63 => { // 'c'
-					match hex_to_compressed_pubkey(line.split_at(2).1) {
-						Some(pk) => {
-							if line.as_bytes()[2 + 33*2] == '@' as u8 {
-								let parse_res: Result<std::net::SocketAddr, _> = line.split_at(2 + 33*2 + 1).1.parse();
-								if let Ok(addr) = parse_res {
-									print!("Attempting to connect to {}...", addr);
-									match std::net::TcpStream::connect_timeout(&addr, Duration::from_secs(10)) {
-										Ok(stream) => {
-											println!("connected, initiating handshake!");
-											let peer_manager = peer_manager.clone();
-											let event_notify = event_notify.clone();
-											join_handles.push(tokio::spawn(async move {
-												setup_outbound(peer_manager, event_notify, pk,
-													tokio::net::TcpStream::from_std(stream).unwrap()).await;
-											}));
-										},
-										Err(e) => {
-											println!("connection failed {:?}!", e);
-										}
-									}
-								} else { println!("Couldn't parse host:port into a socket address"); }
-							} else { println!("Invalid line, should be c pubkey@host:port"); }
-						},
-						None => println!("Bad PubKey for remote node"),
-					}
-				},
-				0x6e => { // 'n'
-					match hex_to_compressed_pubkey(line.split_at(2).1) {
-						Some(pk) => {
-							if line.as_bytes()[2 + 33*2] == ' ' as u8 {
-								let mut args = line.split_at(2 + 33*2 + 1).1.split(' ');
-								if let Some(value_str) = args.next() {
-									if let Some(push_str) = args.next() {
-										if let Ok(value) = value_str.parse() {
-											if let Ok(push) = push_str.parse() {
-												match channel_manager.create_channel(pk, value, push, 0, None) {
-													Ok(_) => println!("Channel created, sending open_channel!"),
-													Err(e) => println!("Failed to open channel: {:?}!", e),
-												}
-												let _ = event_notify.try_send(());
-											} else { println!("Couldn't parse third argument into a push value"); }
-										} else { println!("Couldn't parse second argument into a value"); }
-									} else { println!("Couldn't read third argument"); }
-								} else { println!("Couldn't read second argument"); }
-							} else { println!("Invalid line, should be n pubkey value"); }
-						},
-						None => println!("Bad PubKey for remote node"),
-					}
-				},
-				0x6b => { // 'k'
-					if line.len() == 64 + 2 {
-						if let Some(chan_id_vec) = hex_to_vec(line.split_at(2).1) {
-							let mut channel_id = [0; 32];
-							channel_id.copy_from_slice(&chan_id_vec);
-							match channel_manager.close_channel(&channel_id) {
-								Ok(()) => {
-									println!("Ok, channel closing!");
-									let _ = event_notify.try_send(());
-								},
-								Err(e) => println!("Failed to close channel: {:?}", e),
-							}
-						} else { println!("Bad channel_id hex"); }
-					} else { println!("Bad channel_id hex"); }
-				},
-				0x66 => { // 'f'
-					if line.len() == 5 && line.as_bytes()[2] == 'a' as u8 && line.as_bytes()[3] == 'l' as u8 && line.as_bytes()[4] == 'l' as u8 {
-						channel_manager.force_close_all_channels();
-					} else if line.len() == 66 {
-						if let Some(channel_id) = hex_to_vec(&line[2..]) {
-							channel_manager.force_close_channel(&channel_id[..].try_into().unwrap());
-						} else {
-							println!("Invalid channel id");
-						}
-					} else {
-						println!("Force close should either be all or a channel id");
-					}
-				},
-				0x6c => { // 'l'
-					if line.as_bytes()[2] == 'p' as u8 {
-						let mut nodes = String::new();
-						for node_id in peer_manager.get_peer_node_ids() {
-							nodes += &format!("{}, ", hex_str(&node_id.serialize()));
-						}
-						println!("Connected nodes: {}", nodes);
-					} else if line.as_bytes()[2] == 'c' as u8 {
-						println!("All channels:");
-						for chan_info in channel_manager.list_channels() {
-							if let Some(short_id) = chan_info.short_channel_id {
-								println!("id: {}, short_id: {}, peer: {}, value: {} sat, live: {}", hex_str(&chan_info.channel_id[..]), short_id, hex_str(&chan_info.remote_network_id.serialize()), chan_info.channel_value_satoshis, chan_info.is_live);
-							} else {
-								println!("id: {}, not yet confirmed, peer: {}, value: {} sat, live: {}", hex_str(&chan_info.channel_id[..]), hex_str(&chan_info.remote_network_id.serialize()), chan_info.channel_value_satoshis, chan_info.is_live);
-							}
-						}
-					} else {
-						println!("Listing of non-peer/channel objects not yet implemented");
-					}
-				},
-				0x73 => { // 's'
-					let mut args = line.split_at(2).1.split(' ');
-					match lightning_invoice::Invoice::from_str(args.next().unwrap()) {
-						Ok(invoice) => {
-							if match invoice.currency() {
-								lightning_invoice::Currency::Bitcoin => constants::Network::Bitcoin,
-								lightning_invoice::Currency::BitcoinTestnet => constants::Network::Testnet,
-								lightning_invoice::Currency::Regtest => constants::Network::Regtest,
-							} != network {
-								println!("Wrong network on invoice");
-							} else {
-								let arg2 = args.next();
-								let amt = if let Some(amt) = invoice.amount_pico_btc().and_then(|amt| {
-									if amt % 10 != 0 { None } else { Some(amt / 10) }
-								}) {
-									if arg2.is_some() {
-										println!("Invoice had amount, you shouldn't specify one");
-										fail_return!();
-									}
-									amt
-								} else {
-									if arg2.is_none() {
-										println!("Invoice didn't have an amount, you should specify one");
-										fail_return!();
-									}
-									match arg2.unwrap().parse() {
-										Ok(amt) => amt,
-										Err(_) => {
-											println!("Provided amount was garbage");
-											fail_return!();
-										}
-									}
-								};
+	}
 
-								if let Some(pubkey) = invoice.payee_pub_key() {
-									if *pubkey != invoice.recover_payee_pub_key() {
-										println!("Invoice had non-equal duplicative target node_id (ie was malformed)");
-										fail_return!();
-									}
-								}
-
-								let mut route_hint = Vec::with_capacity(invoice.routes().len());
-								for route in invoice.routes() {
-									if route.len() != 1 {
-										println!("Invoice contained multi-hop non-public route, ignoring as yet unsupported");
-									} else {
-										route_hint.push(router::RouteHint {
-											src_node_id: route[0].pubkey,
-											short_channel_id: slice_to_be64(&route[0].short_channel_id),
-											fee_base_msat: route[0].fee_base_msat,
-											fee_proportional_millionths: route[0].fee_proportional_millionths,
-											cltv_expiry_delta: route[0].cltv_expiry_delta,
-											htlc_minimum_msat: 0,
-										});
-									}
-								}
-
-								let final_cltv = invoice.min_final_cltv_expiry().unwrap_or(&9);
-								if *final_cltv > std::u32::MAX as u64 {
-									println!("Invoice had garbage final cltv");
-									fail_return!();
-								}
-								match router.get_route(&invoice.recover_payee_pub_key(), Some(&channel_manager.list_usable_channels()), &route_hint, amt, *final_cltv as u32) {
-									Ok(route) => {
-										let mut payment_hash = PaymentHash([0; 32]);
-										payment_hash.0.copy_from_slice(&invoice.payment_hash()[..]);
-										match channel_manager.send_payment(&route, payment_hash, &None) {
-											Ok(()) => {
-												println!("Sending {} msat", amt);
-												let _ = event_notify.try_send(());
-											},
-											Err(e) => {
-												println!("Failed to send HTLC: {:?}", e);
-											}
-										}
-									},
-									Err(e) => {
-										println!("Failed to find route: {}", e.err);
-									}
-								}
-							}
-						},
-						Err(_) => {
-							println!("Bad invoice");
-						},
-					}
-				},
-				0x70 => { // 'p'
-					if let Ok(value) = line[2..].parse::<u64>() {
-						let mut payment_preimage = [0; 32];
-						thread_rng().fill_bytes(&mut payment_preimage);
-						let payment_hash = Sha256Hash::hash(&payment_preimage);
-						//TODO: Store this on disk somewhere!
-						payment_preimages.lock().unwrap().insert(PaymentHash(payment_hash.into_inner()), PaymentPreimage(payment_preimage));
-						println!("payment_hash: {}", hex_str(&payment_hash.into_inner()));
-
-						let invoice_res = lightning_invoice::InvoiceBuilder::new(match network {
-								constants::Network::Bitcoin => lightning_invoice::Currency::Bitcoin,
-								constants::Network::Testnet => lightning_invoice::Currency::BitcoinTestnet,
-								constants::Network::Regtest => lightning_invoice::Currency::Regtest,
-							}).payment_hash(payment_hash).description("rust-lightning-bitcoinrpc invoice".to_string())
-							//.route(chans)
-							.amount_pico_btc(value * 10)
-							.current_timestamp()
-							.build_signed(|msg_hash| {
-								secp_ctx.sign_recoverable(msg_hash, &keys.get_node_secret())
-							});
-						match invoice_res {
-							Ok(invoice) => println!("Invoice: {}", invoice),
-							Err(e) => println!("Error creating invoice: {:?}", e),
-						}
-					} else { println!("Invalid value"); }
-				},
-				_ => println!("Unknown command: {}", line.as_bytes()[0] as char),
+	if let Some(path) = control_socket.or_else(|| if daemon { storage_directory(&data_path).map(|dir| format!("{}/control.sock", dir)) } else { None }) {
+		match spawn_control_socket(&path, command_handler.clone()) {
+			Ok(handle) => {
+				join_handles.push(handle);
+				println!("Accepting commands on control socket {}", path);
+			},
+			Err(e) => {
+				println!("Failed to open control socket {}: {}", path, e);
+				return;
 			}
-		} else {
-			println!("Unknown command line: {}", line);
 		}
-		print!("> "); std::io::stdout().flush().unwrap();
+	} else if daemon {
+		println!("In-memory storage has no directory for the control socket, pass --control-socket=");
+		return;
 	}
+
+	if daemon {
+		futures_util::future::pending::<()>().await;
+	}
+
+	println!("Started interactive shell! Commands (tab completes commands, node ids and channel ids):");
+	print!("{}", command_help());
+	let history_path = storage_directory(&data_path).map(|dir| format!("{}/shell_history", dir));
+	let runtime = tokio::runtime::Handle::current();
+	let _ = tokio::task::spawn_blocking(move || run_shell(command_handler, history_path, runtime)).await;
 }
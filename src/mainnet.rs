@@ -0,0 +1,46 @@
+use crate::config::*;
+
+use std::io::{BufRead, Write};
+
+/// If bitcoind is pruned, we require it to still have at least this many blocks so that we can
+/// re-scan for channel activity after being offline for a while. Note that we won't notice
+/// counterparties broadcasting revoked states while we're offline for longer than our
+/// to_self_delay anyway.
+const MIN_UNPRUNED_BLOCKS: u64 = 4032;
+
+const MAINNET_CONFIRMATION: &str = "I understand I may lose funds";
+
+/// Asks the operator to confirm they really want to run on mainnet, unless they already have in
+/// the config file.
+pub fn confirm_mainnet(node_config: &NodeConfig) -> bool {
+	if node_config.mainnet_confirmed { return true; }
+	println!("This node is experimental software and bugs in it may cause you to lose funds.");
+	print!("Type \"{}\" to run it on mainnet anyway: ", MAINNET_CONFIRMATION);
+	std::io::stdout().flush().unwrap();
+	let mut line = String::new();
+	if std::io::stdin().lock().read_line(&mut line).is_err() { return false; }
+	line.trim() == MAINNET_CONFIRMATION
+}
+
+/// Checks that we're set up to not lose funds before we start on mainnet, returning each problem
+/// found. Encryption of key_seed is checked separately when we load it.
+pub fn mainnet_safety_problems(node_config: &NodeConfig, storage_path: &str, monitor_replicas: usize, chain_info: &serde_json::Value) -> Vec<String> {
+	let mut problems = Vec::new();
+	if storage_path == "memory:" {
+		problems.push("In-memory storage loses all channel state on exit, use a storage directory".to_string());
+	}
+	if monitor_replicas == 0 {
+		problems.push("No monitor replicas configured, losing the storage directory's disk would lose all channel funds. Add at least one --monitor-replica".to_string());
+	}
+	if node_config.max_total_channel_capacity_sat.is_none() {
+		problems.push("channels.max_total_capacity_satoshis must be set to cap how much can be put at risk in channels".to_string());
+	}
+	if chain_info["pruned"].as_bool() == Some(true) {
+		let blocks = chain_info["blocks"].as_u64().unwrap_or(0);
+		let prune_height = chain_info["pruneheight"].as_u64().unwrap_or(blocks);
+		if blocks.saturating_sub(prune_height) < MIN_UNPRUNED_BLOCKS {
+			problems.push(format!("bitcoind is pruned to only {} blocks, we require at least {} to rescan for channel activity after downtime", blocks.saturating_sub(prune_height), MIN_UNPRUNED_BLOCKS));
+		}
+	}
+	problems
+}
@@ -0,0 +1,239 @@
+use crate::peer_filter::*;
+
+use lightning_net_tokio::*;
+
+use lightning::ln::msgs;
+
+use bitcoin::secp256k1::key::PublicKey;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Tor circuits can take a while to build, so give connections through the proxy longer.
+const PROXY_CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Somewhere a peer can be reached, either an IP address or a hostname (including Tor .onion
+/// addresses) which we resolve (or have the proxy resolve) when connecting.
+#[derive(Clone, PartialEq)]
+pub enum PeerAddress {
+	Socket(SocketAddr),
+	Host(String, u16),
+}
+impl PeerAddress {
+	pub fn is_onion(&self) -> bool {
+		match self {
+			PeerAddress::Host(host, _) => host.ends_with(".onion"),
+			PeerAddress::Socket(_) => false,
+		}
+	}
+
+	/// Converts an address from a node_announcement.
+	pub fn from_net_address(addr: &msgs::NetAddress) -> Self {
+		match addr {
+			msgs::NetAddress::IPv4 { addr, port } => PeerAddress::Socket(SocketAddr::new((*addr).into(), *port)),
+			msgs::NetAddress::IPv6 { addr, port } => PeerAddress::Socket(SocketAddr::new((*addr).into(), *port)),
+			msgs::NetAddress::OnionV2 { addr, port } => PeerAddress::Host(onion_base32(addr) + ".onion", *port),
+			msgs::NetAddress::OnionV3 { ed25519_pubkey, checksum, version, port } => {
+				let mut onion = ed25519_pubkey.to_vec();
+				onion.extend_from_slice(&checksum.to_be_bytes());
+				onion.push(*version);
+				PeerAddress::Host(onion_base32(&onion) + ".onion", *port)
+			},
+		}
+	}
+
+	/// Converts an address for our own node_announcement, which can carry IP addresses and .onion
+	/// addresses but not other hostnames.
+	pub fn to_net_address(&self) -> Option<msgs::NetAddress> {
+		match self {
+			PeerAddress::Socket(SocketAddr::V4(sockaddr)) => Some(msgs::NetAddress::IPv4 { addr: sockaddr.ip().octets(), port: sockaddr.port() }),
+			PeerAddress::Socket(SocketAddr::V6(sockaddr)) => Some(msgs::NetAddress::IPv6 { addr: sockaddr.ip().octets(), port: sockaddr.port() }),
+			PeerAddress::Host(host, port) if self.is_onion() => {
+				let onion = onion_base32_decode(&host[..host.len() - ".onion".len()])?;
+				match onion.len() {
+					10 => {
+						let mut addr = [0; 10];
+						addr.copy_from_slice(&onion);
+						Some(msgs::NetAddress::OnionV2 { addr, port: *port })
+					},
+					35 => {
+						let mut ed25519_pubkey = [0; 32];
+						ed25519_pubkey.copy_from_slice(&onion[..32]);
+						Some(msgs::NetAddress::OnionV3 {
+							ed25519_pubkey,
+							checksum: ((onion[32] as u16) << 8) | onion[33] as u16,
+							version: onion[34],
+							port: *port,
+						})
+					},
+					_ => None,
+				}
+			},
+			PeerAddress::Host(_, _) => None,
+		}
+	}
+}
+
+const ONION_BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Encodes data as unpadded lowercase base32, the way Tor encodes .onion hostnames.
+fn onion_base32(data: &[u8]) -> String {
+	let mut res = String::new();
+	let mut buffer: u16 = 0;
+	let mut bits = 0;
+	for byte in data {
+		buffer = (buffer << 8) | *byte as u16;
+		bits += 8;
+		while bits >= 5 {
+			res.push(ONION_BASE32_ALPHABET[((buffer >> (bits - 5)) & 31) as usize] as char);
+			bits -= 5;
+		}
+	}
+	if bits > 0 {
+		res.push(ONION_BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+	}
+	res
+}
+
+fn onion_base32_decode(s: &str) -> Option<Vec<u8>> {
+	let mut res = Vec::new();
+	let mut buffer: u16 = 0;
+	let mut bits = 0;
+	for c in s.to_ascii_lowercase().bytes() {
+		buffer = (buffer << 5) | ONION_BASE32_ALPHABET.iter().position(|a| *a == c)? as u16;
+		bits += 5;
+		if bits >= 8 {
+			res.push((buffer >> (bits - 8)) as u8);
+			bits -= 8;
+		}
+	}
+	Some(res)
+}
+
+impl FromStr for PeerAddress {
+	type Err = String;
+	fn from_str(s: &str) -> Result<Self, String> {
+		if let Ok(addr) = s.parse() {
+			return Ok(PeerAddress::Socket(addr));
+		}
+		let mut parts = s.rsplitn(2, ':');
+		let port = parts.next().unwrap().parse().map_err(|_| format!("Couldn't parse {} into host:port", s))?;
+		match parts.next() {
+			// Anything with a colon left over is a mangled IPv6 address, which must be in brackets.
+			Some(host) if !host.is_empty() && host.len() <= 255 && !host.contains(':') => Ok(PeerAddress::Host(host.to_string(), port)),
+			_ => Err(format!("Couldn't parse {} into host:port", s)),
+		}
+	}
+}
+impl fmt::Display for PeerAddress {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			PeerAddress::Socket(addr) => write!(f, "{}", addr),
+			PeerAddress::Host(host, port) => write!(f, "{}:{}", host, port),
+		}
+	}
+}
+
+fn socks5_error(code: u8) -> &'static str {
+	match code {
+		1 => "general failure",
+		2 => "connection not allowed by ruleset",
+		3 => "network unreachable",
+		4 => "host unreachable",
+		5 => "connection refused",
+		6 => "TTL expired",
+		7 => "command not supported",
+		8 => "address type not supported",
+		_ => "unknown error",
+	}
+}
+
+/// Connects to addr through the SOCKS5 proxy at proxy (eg Tor), letting the proxy resolve
+/// hostnames so that .onion addresses work and DNS lookups don't leak.
+async fn socks5_connect(proxy: SocketAddr, addr: &PeerAddress) -> Result<TcpStream, String> {
+	let io_err = |e: std::io::Error| format!("proxy connection failed: {}", e);
+	let mut stream = TcpStream::connect(proxy).await.map_err(io_err)?;
+	// Version 5, offering only the "no authentication" method.
+	stream.write_all(&[5, 1, 0]).await.map_err(io_err)?;
+	let mut method = [0; 2];
+	stream.read_exact(&mut method).await.map_err(io_err)?;
+	if method != [5, 0] {
+		return Err("proxy requires authentication, which we don't support".to_string());
+	}
+
+	let mut request = vec![5, 1, 0]; // CONNECT
+	let port = match addr {
+		PeerAddress::Socket(SocketAddr::V4(sockaddr)) => {
+			request.push(1);
+			request.extend_from_slice(&sockaddr.ip().octets());
+			sockaddr.port()
+		},
+		PeerAddress::Socket(SocketAddr::V6(sockaddr)) => {
+			request.push(4);
+			request.extend_from_slice(&sockaddr.ip().octets());
+			sockaddr.port()
+		},
+		PeerAddress::Host(host, port) => {
+			request.push(3);
+			request.push(host.len() as u8);
+			request.extend_from_slice(host.as_bytes());
+			*port
+		},
+	};
+	request.extend_from_slice(&port.to_be_bytes());
+	stream.write_all(&request).await.map_err(io_err)?;
+
+	let mut reply = [0; 4];
+	stream.read_exact(&mut reply).await.map_err(io_err)?;
+	if reply[1] != 0 {
+		return Err(format!("proxy failed to connect: {}", socks5_error(reply[1])));
+	}
+	// Skip the address the proxy bound, which we don't care about.
+	let bound_addr_len = match reply[3] {
+		1 => 4,
+		4 => 16,
+		3 => {
+			let mut len = [0; 1];
+			stream.read_exact(&mut len).await.map_err(io_err)?;
+			len[0] as usize
+		},
+		_ => return Err("proxy sent a malformed reply".to_string()),
+	};
+	let mut bound_addr = vec![0; bound_addr_len + 2];
+	stream.read_exact(&mut bound_addr).await.map_err(io_err)?;
+	Ok(stream)
+}
+
+/// Opens a TCP connection to addr, through proxy if one is configured, resolving hostnames
+/// without blocking.
+pub async fn open_connection(addr: &PeerAddress, proxy: Option<SocketAddr>) -> Result<TcpStream, String> {
+	match proxy {
+		Some(proxy) => tokio::time::timeout(PROXY_CONNECT_TIMEOUT, socks5_connect(proxy, addr)).await
+			.map_err(|_| "connection through proxy timed out".to_string())?,
+		None if addr.is_onion() => Err("Connecting to .onion addresses requires network.proxy to point at a Tor SOCKS5 proxy".to_string()),
+		None => {
+			let connect = match addr {
+				PeerAddress::Socket(sockaddr) => tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(*sockaddr)).await,
+				PeerAddress::Host(host, port) => tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect((&host[..], *port))).await,
+			};
+			connect.map_err(|_| "connection timed out".to_string())?
+				.map_err(|e| format!("connection failed: {}", e))
+		},
+	}
+}
+
+/// Connects to the given peer, returning a handle to the task running the connection once the
+/// TCP connection is open (the handshake then happens in that task).
+pub async fn connect_outbound(peer_manager: FilteredPeerManager, event_notify: mpsc::Sender<()>, their_node_id: PublicKey, addr: &PeerAddress, proxy: Option<SocketAddr>) -> Result<tokio::task::JoinHandle<()>, String> {
+	let stream = open_connection(addr, proxy).await?;
+	Ok(tokio::spawn(async move {
+		setup_outbound(peer_manager, event_notify, their_node_id, stream).await;
+	}))
+}
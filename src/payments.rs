@@ -0,0 +1,355 @@
+use crate::ChannelMonitor;
+use crate::chain_monitor::{ChainInterface, FeeEstimator};
+use crate::invoices::*;
+use crate::utils::*;
+
+use lightning::ln::{channelmanager, router};
+use lightning::ln::channelmanager::{PaymentHash, PaymentSecret, PaymentSendFailure};
+
+use bitcoin::secp256k1::key::PublicKey;
+use bitcoin::network::constants;
+
+use rand::{thread_rng, Rng};
+
+use std::cmp;
+use std::collections::HashMap;
+use std::slice;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// Everything we need to re-route a payment, kept around until it either completes or we give up
+/// on it.
+struct PendingPayment {
+	payee: PublicKey,
+	route_hints: Vec<router::RouteHint>,
+	amt_msat: u64,
+	final_cltv: u32,
+	payment_secret: Option<PaymentSecret>,
+	parts_in_flight: usize,
+	/// Set once a part of the current attempt fails while others are still out. rust-lightning
+	/// sets the MPP total_msat of every part from the route it was sent with, so a replacement
+	/// part couldn't join the others. Instead the recipient holds them until its MPP timeout and
+	/// fails them back, after which we re-route the whole amount.
+	incomplete: bool,
+	attempts: usize,
+}
+
+/// Decodes an invoice we've been asked to pay, checking that it's for our network and working out
+/// the amount to send, which must come from exactly one of the invoice or amt_arg.
+pub fn parse_invoice_to_pay(invoice_str: &str, amt_arg: Option<&str>, network: constants::Network) -> Result<(lightning_invoice::Invoice, PaymentHash, u64, u32), String> {
+	let invoice = lightning_invoice::Invoice::from_str(invoice_str).map_err(|e| describe_invoice_error(invoice_str, &e))?;
+	if currency_to_network(invoice.currency()) != network {
+		return Err("Wrong network on invoice".to_string());
+	}
+
+	let amt = if let Some(amt) = invoice.amount_pico_btc().and_then(|amt| {
+		if amt % 10 != 0 { None } else { Some(amt / 10) }
+	}) {
+		if amt_arg.is_some() {
+			return Err("Invoice had amount, you shouldn't specify one".to_string());
+		}
+		amt
+	} else {
+		match amt_arg {
+			None => return Err("Invoice didn't have an amount, you should specify one".to_string()),
+			Some(amt_str) => amt_str.parse().map_err(|_| "Provided amount was garbage".to_string())?,
+		}
+	};
+
+	if let Some(pubkey) = invoice.payee_pub_key() {
+		if *pubkey != invoice.recover_payee_pub_key() {
+			return Err("Invoice had non-equal duplicative target node_id (ie was malformed)".to_string());
+		}
+	}
+
+	let final_cltv = invoice.min_final_cltv_expiry().unwrap_or(&9);
+	if *final_cltv > std::u32::MAX as u64 {
+		return Err("Invoice had garbage final cltv".to_string());
+	}
+	let mut payment_hash = PaymentHash([0; 32]);
+	payment_hash.0.copy_from_slice(&invoice.payment_hash()[..]);
+	Ok((invoice, payment_hash, amt, *final_cltv as u32))
+}
+
+pub fn route_hints_from_invoice(invoice: &lightning_invoice::Invoice) -> Vec<router::RouteHint> {
+	let mut route_hint = Vec::with_capacity(invoice.routes().len());
+	for route in invoice.routes() {
+		if route.len() != 1 {
+			println!("Invoice contained multi-hop non-public route, ignoring as yet unsupported");
+		} else {
+			route_hint.push(router::RouteHint {
+				src_node_id: route[0].pubkey,
+				short_channel_id: slice_to_be64(&route[0].short_channel_id),
+				fee_base_msat: route[0].fee_base_msat,
+				fee_proportional_millionths: route[0].fee_proportional_millionths,
+				cltv_expiry_delta: route[0].cltv_expiry_delta,
+				htlc_minimum_msat: 0,
+			});
+		}
+	}
+	route_hint
+}
+
+/// The total amount which leaves our node over the first hop of the given path (ie the payment
+/// amount plus all fees).
+pub fn path_total_msat(path: &[router::RouteHop]) -> u64 {
+	path.iter().map(|hop| hop.fee_msat).sum()
+}
+
+/// The total fees paid to intermediate nodes across all paths in the given route.
+pub fn route_fee_msat(route: &router::Route, amt_msat: u64) -> u64 {
+	route.paths.iter().map(|path| path_total_msat(path)).sum::<u64>() - amt_msat
+}
+
+pub fn format_route_quote(route: &router::Route, amt_msat: u64) -> String {
+	let mut res = format!("Route for {} msat over {} path(s), total fees {} msat:\n", amt_msat, route.paths.len(), route_fee_msat(route, amt_msat));
+	for (idx, path) in route.paths.iter().enumerate() {
+		let cltv: u32 = path.iter().map(|hop| hop.cltv_expiry_delta).sum();
+		let delivered = path.last().unwrap().fee_msat;
+		res += &format!(" path {}: {} msat, fees {} msat, total CLTV delta {}\n", idx, delivered, path_total_msat(path) - delivered, cltv);
+		for hop in path.iter() {
+			res += &format!("  -> {} via {}, fee {} msat, CLTV delta {}\n", hex_str(&hop.pubkey.serialize()), hop.short_channel_id, hop.fee_msat, hop.cltv_expiry_delta);
+		}
+	}
+	res
+}
+
+/// Finds a route for amt_msat to payee. If no single one of our channels has enough outbound
+/// capacity to carry the whole payment, it is split across up to max_paths of them, largest first.
+pub fn get_mpp_route(router: &router::Router, first_hops: &[channelmanager::ChannelDetails], payee: &PublicKey, route_hints: &[router::RouteHint], amt_msat: u64, final_cltv: u32, max_paths: usize) -> Result<router::Route, String> {
+	// First see if the router's choice of path fits in the channel it picked, which is the common
+	// case and preserves its preference for short routes.
+	let single_err = match router.get_route(payee, Some(first_hops), route_hints, amt_msat, final_cltv) {
+		Ok(route) => {
+			let first_scid = route.paths[0][0].short_channel_id;
+			if first_hops.iter().any(|chan| chan.short_channel_id == Some(first_scid) &&
+					chan.outbound_capacity_msat >= path_total_msat(&route.paths[0])) {
+				return Ok(route);
+			}
+			"insufficient outbound capacity in any single channel".to_string()
+		},
+		Err(e) => e.err.to_string(),
+	};
+	if max_paths < 2 {
+		return Err(single_err);
+	}
+
+	let mut chans: Vec<&channelmanager::ChannelDetails> = first_hops.iter().collect();
+	chans.sort_unstable_by(|a, b| b.outbound_capacity_msat.cmp(&a.outbound_capacity_msat));
+
+	let mut paths = Vec::new();
+	let mut remaining = amt_msat;
+	for chan in chans {
+		if remaining == 0 || paths.len() >= max_paths { break; }
+		let mut part = cmp::min(remaining, chan.outbound_capacity_msat);
+		// Fees for later hops are paid out of the first hop's capacity, so if the path we get back
+		// doesn't fit, shrink the part by the overage and try once more.
+		for _ in 0..2 {
+			if part == 0 { break; }
+			match router.get_route(payee, Some(slice::from_ref(chan)), route_hints, part, final_cltv) {
+				Ok(mut route) => {
+					let total = path_total_msat(&route.paths[0]);
+					if total <= chan.outbound_capacity_msat {
+						paths.push(route.paths.remove(0));
+						remaining -= part;
+						break;
+					}
+					part = part.saturating_sub(total - chan.outbound_capacity_msat);
+				},
+				Err(_) => break,
+			}
+		}
+	}
+	if remaining != 0 {
+		return Err(format!("only able to route {} of {} msat over {} paths ({})", amt_msat - remaining, amt_msat, paths.len(), single_err));
+	}
+	Ok(router::Route { paths })
+}
+
+/// Tracks outbound payments which may have been split into several parts, retrying a payment with
+/// a fresh route once all of its in-flight parts have failed.
+pub struct PaymentTracker {
+	pending: Mutex<HashMap<PaymentHash, PendingPayment>>,
+	/// Probes we've sent with a random payment_hash, mapped to the number of parts still in flight.
+	probes: Mutex<HashMap<PaymentHash, usize>>,
+	max_paths: usize,
+	max_attempts: usize,
+	max_fee_base_msat: u64,
+	max_fee_proportional_millionths: u64,
+}
+impl PaymentTracker {
+	pub fn new(max_paths: usize, max_attempts: usize, max_fee_base_msat: u64, max_fee_proportional_millionths: u64) -> Self {
+		PaymentTracker {
+			pending: Mutex::new(HashMap::new()),
+			probes: Mutex::new(HashMap::new()),
+			max_paths,
+			max_attempts,
+			max_fee_base_msat,
+			max_fee_proportional_millionths,
+		}
+	}
+
+	pub fn max_fee_msat(&self, amt_msat: u64) -> u64 {
+		cmp::max(self.max_fee_base_msat, amt_msat.saturating_mul(self.max_fee_proportional_millionths) / 1_000_000)
+	}
+
+	/// Finds the route we'd use to pay the given invoice, without sending anything.
+	pub fn quote(&self, router: &router::Router, channel_manager: &channelmanager::SimpleArcChannelManager<ChannelMonitor, ChainInterface, FeeEstimator>, invoice: &lightning_invoice::Invoice, amt_msat: u64, final_cltv: u32) -> Result<router::Route, String> {
+		let route = get_mpp_route(router, &channel_manager.list_usable_channels(), &invoice.recover_payee_pub_key(), &route_hints_from_invoice(invoice), amt_msat, final_cltv, self.max_paths)
+			.map_err(|e| format!("Failed to find route: {}", e))?;
+		let fee = route_fee_msat(&route, amt_msat);
+		if fee > self.max_fee_msat(amt_msat) {
+			println!("WARNING: route fee of {} msat exceeds our maximum of {} msat, payment would be refused", fee, self.max_fee_msat(amt_msat));
+		}
+		Ok(route)
+	}
+
+	/// Sends an HTLC along the given route with a random payment_hash. The recipient can't claim it,
+	/// so if it rejects it (rather than some hop along the way failing it) we know the route has
+	/// enough liquidity to carry the real payment.
+	pub fn send_probe(&self, channel_manager: &channelmanager::SimpleArcChannelManager<ChannelMonitor, ChainInterface, FeeEstimator>, route: &router::Route) -> Result<PaymentHash, String> {
+		let mut probe_hash = PaymentHash([0; 32]);
+		thread_rng().fill_bytes(&mut probe_hash.0);
+		// The invoice's payment_secret would be revealed to every hop (which can't tell a probe from
+		// a real payment), so never use it. Multi-path payments require some secret though, so
+		// those get a random one.
+		let payment_secret = if route.paths.len() > 1 {
+			let mut secret = PaymentSecret([0; 32]);
+			thread_rng().fill_bytes(&mut secret.0);
+			Some(secret)
+		} else { None };
+		let parts = match channel_manager.send_payment(route, probe_hash, &payment_secret) {
+			Ok(()) => route.paths.len(),
+			Err(PaymentSendFailure::PartialFailure(results)) => {
+				let parts = results.iter().filter(|res| res.is_ok()).count();
+				if parts == 0 {
+					return Err(format!("Every probe part failed to send: {:?}", results));
+				}
+				parts
+			},
+			Err(e) => return Err(format!("Failed to send probe: {:?}", e)),
+		};
+		self.probes.lock().unwrap().insert(probe_hash, parts);
+		Ok(probe_hash)
+	}
+
+	fn try_send(&self, router: &router::Router, channel_manager: &channelmanager::SimpleArcChannelManager<ChannelMonitor, ChainInterface, FeeEstimator>, payment_hash: PaymentHash, payment: &mut PendingPayment) -> Result<(), String> {
+		payment.attempts += 1;
+		let route = get_mpp_route(router, &channel_manager.list_usable_channels(), &payment.payee, &payment.route_hints, payment.amt_msat, payment.final_cltv, self.max_paths)
+			.map_err(|e| format!("Failed to find route: {}", e))?;
+		let fee = route_fee_msat(&route, payment.amt_msat);
+		if fee > self.max_fee_msat(payment.amt_msat) {
+			return Err(format!("Route fee of {} msat exceeds our maximum of {} msat", fee, self.max_fee_msat(payment.amt_msat)));
+		}
+		if route.paths.len() > 1 && payment.payment_secret.is_none() {
+			return Err("Payment needs to be split over multiple paths but the invoice has no payment secret".to_string());
+		}
+		payment.incomplete = false;
+		match channel_manager.send_payment(&route, payment_hash, &payment.payment_secret) {
+			Ok(()) => {
+				payment.parts_in_flight = route.paths.len();
+				Ok(())
+			},
+			Err(PaymentSendFailure::PartialFailure(results)) => {
+				// Whatever parts went out will be resolved via events like any other, the rest
+				// count as failed immediately.
+				payment.parts_in_flight = results.iter().filter(|res| res.is_ok()).count();
+				if payment.parts_in_flight == 0 {
+					return Err(format!("Every payment part failed to send: {:?}", results));
+				}
+				println!("Only {} of {} parts of payment {} were sent, re-routing once the recipient returns them: {:?}", payment.parts_in_flight, route.paths.len(), hex_str(&payment_hash.0), results);
+				payment.incomplete = true;
+				Ok(())
+			},
+			Err(e) => Err(format!("Failed to send HTLC: {:?}", e)),
+		}
+	}
+
+	/// Sends a payment, returning the number of parts which went out and whether some others
+	/// failed to, in which case the payment will be re-routed once the recipient returns them.
+	pub fn send_payment(&self, router: &router::Router, channel_manager: &channelmanager::SimpleArcChannelManager<ChannelMonitor, ChainInterface, FeeEstimator>, invoice: &lightning_invoice::Invoice, payment_hash: PaymentHash, amt_msat: u64, final_cltv: u32) -> Result<(usize, bool), String> {
+		let mut pending = self.pending.lock().unwrap();
+		if pending.contains_key(&payment_hash) {
+			return Err("Already have a pending payment for that payment_hash".to_string());
+		}
+		let mut payment = PendingPayment {
+			payee: invoice.recover_payee_pub_key(),
+			route_hints: route_hints_from_invoice(invoice),
+			amt_msat,
+			final_cltv,
+			payment_secret: invoice.payment_secret().map(|secret| PaymentSecret(secret.0)),
+			parts_in_flight: 0,
+			incomplete: false,
+			attempts: 0,
+		};
+		self.try_send(router, channel_manager, payment_hash, &mut payment)?;
+		let parts = payment.parts_in_flight;
+		let incomplete = payment.incomplete;
+		pending.insert(payment_hash, payment);
+		Ok((parts, incomplete))
+	}
+
+	/// Handles a claimed part of a payment, returning true the first time for each payment.
+	pub fn payment_sent(&self, payment_hash: &PaymentHash) -> bool {
+		// Once one part has been claimed the recipient has everything, the remaining parts will be
+		// claimed (and generate their own PaymentSent events) shortly.
+		if self.pending.lock().unwrap().remove(payment_hash).is_some() {
+			println!("Payment {} completed!", hex_str(&payment_hash.0));
+			true
+		} else { false }
+	}
+
+	/// Handles a failed part of a payment, returning true if we tried to send it again.
+	pub fn payment_failed(&self, router: &router::Router, channel_manager: &channelmanager::SimpleArcChannelManager<ChannelMonitor, ChainInterface, FeeEstimator>, payment_hash: &PaymentHash, rejected_by_dest: bool) -> bool {
+		{
+			let mut probes = self.probes.lock().unwrap();
+			if let Some(parts) = probes.get_mut(payment_hash) {
+				if rejected_by_dest {
+					println!("Probe {} reached the recipient, route has sufficient liquidity", hex_str(&payment_hash.0));
+				} else {
+					println!("Probe {} failed before reaching the recipient", hex_str(&payment_hash.0));
+				}
+				*parts -= 1;
+				if *parts == 0 {
+					probes.remove(payment_hash);
+				}
+				return false;
+			}
+		}
+		let mut pending = self.pending.lock().unwrap();
+		let abandon = match pending.get_mut(payment_hash) {
+			Some(payment) => {
+				payment.parts_in_flight = payment.parts_in_flight.saturating_sub(1);
+				if rejected_by_dest && !payment.incomplete {
+					println!("Payment {} rejected by the recipient, giving up", hex_str(&payment_hash.0));
+					true
+				} else if payment.parts_in_flight != 0 {
+					// The parts still out can't add up to the full amount any more, so the recipient
+					// will fail them back (rejecting them itself) once it times out waiting for the rest.
+					payment.incomplete = true;
+					println!("Part of payment {} failed, waiting for the recipient to return {} more part(s) before re-routing", hex_str(&payment_hash.0), payment.parts_in_flight);
+					return false;
+				} else if payment.attempts >= self.max_attempts {
+					println!("Payment {} failed after {} attempts, giving up", hex_str(&payment_hash.0), payment.attempts);
+					true
+				} else {
+					match self.try_send(router, channel_manager, *payment_hash, payment) {
+						Ok(()) => {
+							println!("Retrying payment {} (attempt {})", hex_str(&payment_hash.0), payment.attempts);
+							return true;
+						},
+						Err(e) => {
+							println!("Retrying payment {} failed: {}", hex_str(&payment_hash.0), e);
+							true
+						},
+					}
+				}
+			},
+			None => return false,
+		};
+		if abandon {
+			pending.remove(payment_hash);
+		}
+		false
+	}
+}
@@ -0,0 +1,287 @@
+use crate::chain_monitor::*;
+use crate::utils::*;
+
+use lightning_net_tokio::SocketDescriptor;
+
+use lightning::ln::{channelmanager, msgs, peer_handler};
+use lightning::ln::features::InitFeatures;
+use lightning::util::events::{MessageSendEvent, MessageSendEventsProvider};
+
+use tokio::sync::mpsc;
+
+use bitcoin::secp256k1::key::PublicKey;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Our PeerManager, which hands channel messages to a PeerFilter rather than directly to the
+/// ChannelManager.
+pub type FilteredPeerManager = Arc<peer_handler::PeerManager<SocketDescriptor, Arc<PeerFilter>>>;
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// An IP address or CIDR range, eg 10.0.0.0/8.
+pub struct IpRange {
+	addr: IpAddr,
+	prefix_len: u8,
+}
+impl IpRange {
+	pub fn parse(s: &str) -> Option<Self> {
+		let mut parts = s.splitn(2, '/');
+		let addr: IpAddr = parts.next().unwrap().parse().ok()?;
+		let max_len = if addr.is_ipv4() { 32 } else { 128 };
+		let prefix_len = match parts.next() {
+			Some(len) => len.parse().ok().filter(|len| *len <= max_len)?,
+			None => max_len,
+		};
+		Some(Self { addr, prefix_len })
+	}
+
+	fn contains(&self, ip: &IpAddr) -> bool {
+		let (range, ip, len) = match (self.addr, canonical_ip(*ip)) {
+			(IpAddr::V4(range), IpAddr::V4(ip)) => (u32::from(range) as u128, u32::from(ip) as u128, 32),
+			(IpAddr::V6(range), IpAddr::V6(ip)) => (u128::from(range), u128::from(ip), 128),
+			_ => return false,
+		};
+		let shift = len - self.prefix_len as u32;
+		shift >= len || (range >> shift) == (ip >> shift)
+	}
+}
+
+/// Connections to our [::] listener from IPv4 peers show up as IPv4-mapped IPv6 addresses, which
+/// should match IPv4 ranges.
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+	if let IpAddr::V6(v6) = ip {
+		let segments = v6.segments();
+		if segments[..5] == [0; 5] && segments[5] == 0xffff {
+			return IpAddr::V4(std::net::Ipv4Addr::new((segments[6] >> 8) as u8, segments[6] as u8, (segments[7] >> 8) as u8, segments[7] as u8));
+		}
+	}
+	ip
+}
+
+/// Who we're willing to talk to. Empty allow lists allow everyone not denied.
+pub struct PeerPolicy {
+	pub max_inbound: usize,
+	pub max_inbound_per_ip_per_minute: usize,
+	pub allow_node_ids: Vec<PublicKey>,
+	pub deny_node_ids: Vec<PublicKey>,
+	pub allow_ips: Vec<IpRange>,
+	pub deny_ips: Vec<IpRange>,
+}
+impl PeerPolicy {
+	pub fn node_allowed(&self, node_id: &PublicKey) -> bool {
+		!self.deny_node_ids.contains(node_id) && (self.allow_node_ids.is_empty() || self.allow_node_ids.contains(node_id))
+	}
+
+	fn ip_allowed(&self, ip: &IpAddr) -> bool {
+		!self.deny_ips.iter().any(|range| range.contains(ip)) && (self.allow_ips.is_empty() || self.allow_ips.iter().any(|range| range.contains(ip)))
+	}
+}
+
+/// Which channels opened to us we accept, on top of rust-lightning's own checks.
+pub struct ChannelAcceptancePolicy {
+	pub min_funding_satoshis: u64,
+	pub max_funding_satoshis: Option<u64>,
+	/// If non-empty, only these peers may open channels to us.
+	pub allow_node_ids: Vec<PublicKey>,
+	/// The most unconfirmed channels we'll have with any one peer.
+	pub max_pending_per_peer: Option<usize>,
+	/// The longest the opener may make us wait to claim our funds after we force close.
+	pub max_to_self_delay: u16,
+	/// channels.max_total_capacity_satoshis, which inbound channels may not push us past either.
+	pub max_total_capacity_satoshis: Option<u64>,
+}
+
+/// Decides whether to accept each inbound TCP connection, before the handshake starts.
+pub struct InboundLimiter {
+	policy: Arc<PeerPolicy>,
+	inbound_count: Mutex<usize>,
+	recent_connections: Mutex<HashMap<IpAddr, Vec<Instant>>>,
+}
+impl InboundLimiter {
+	pub fn new(policy: Arc<PeerPolicy>) -> Self {
+		Self { policy, inbound_count: Mutex::new(0), recent_connections: Mutex::new(HashMap::new()) }
+	}
+
+	/// Checks a new inbound connection from ip, counting it if it's accepted. Every accepted
+	/// connection must be passed to connection_closed once it closes.
+	pub fn accept(&self, ip: IpAddr) -> Result<(), String> {
+		let ip = canonical_ip(ip);
+		if !self.policy.ip_allowed(&ip) {
+			return Err("IP address not allowed".to_string());
+		}
+
+		let now = Instant::now();
+		let mut recent_connections = self.recent_connections.lock().unwrap();
+		recent_connections.retain(|_, times| {
+			times.retain(|time| now.duration_since(*time) < RATE_LIMIT_WINDOW);
+			!times.is_empty()
+		});
+		let times = recent_connections.entry(ip).or_insert(Vec::new());
+		if times.len() >= self.policy.max_inbound_per_ip_per_minute {
+			return Err("too many connections from this IP address in the last minute".to_string());
+		}
+		times.push(now);
+
+		let mut inbound_count = self.inbound_count.lock().unwrap();
+		if *inbound_count >= self.policy.max_inbound {
+			return Err(format!("already have the maximum of {} inbound connections", self.policy.max_inbound));
+		}
+		*inbound_count += 1;
+		Ok(())
+	}
+
+	pub fn connection_closed(&self) {
+		*self.inbound_count.lock().unwrap() -= 1;
+	}
+}
+
+/// Sits between the PeerManager and the ChannelManager, disconnecting peers our PeerPolicy
+/// doesn't allow as soon as their handshake completes (which is the first we learn their node_id).
+pub struct PeerFilter {
+	channel_manager: channelmanager::SimpleArcChannelManager<crate::ChannelMonitor, ChainInterface, FeeEstimator>,
+	policy: Arc<PeerPolicy>,
+	channel_policy: ChannelAcceptancePolicy,
+	pending_msg_events: Mutex<Vec<MessageSendEvent>>,
+	/// When recovering from a channel backup, the channel ids and per-commitment points peers
+	/// send us in channel_reestablish, which we need to derive the keys our balances are paid to.
+	recovery_points: Option<mpsc::UnboundedSender<([u8; 32], PublicKey)>>,
+}
+impl PeerFilter {
+	pub fn new(channel_manager: channelmanager::SimpleArcChannelManager<crate::ChannelMonitor, ChainInterface, FeeEstimator>, policy: Arc<PeerPolicy>, channel_policy: ChannelAcceptancePolicy, recovery_points: Option<mpsc::UnboundedSender<([u8; 32], PublicKey)>>) -> Self {
+		Self { channel_manager, policy, channel_policy, pending_msg_events: Mutex::new(Vec::new()), recovery_points }
+	}
+
+	/// Messages from disallowed peers which arrive before we've disconnected them are dropped.
+	fn allowed(&self, node_id: &PublicKey) -> bool {
+		self.policy.node_allowed(node_id)
+	}
+
+	fn check_open_channel(&self, their_node_id: &PublicKey, msg: &msgs::OpenChannel) -> Result<(), String> {
+		let policy = &self.channel_policy;
+		if !policy.allow_node_ids.is_empty() && !policy.allow_node_ids.contains(their_node_id) {
+			return Err("we don't accept channels from this peer".to_string());
+		}
+		if msg.funding_satoshis < policy.min_funding_satoshis {
+			return Err(format!("channels must be at least {} sat", policy.min_funding_satoshis));
+		}
+		if let Some(max) = policy.max_funding_satoshis {
+			if msg.funding_satoshis > max {
+				return Err(format!("channels must be at most {} sat", max));
+			}
+		}
+		if msg.to_self_delay > policy.max_to_self_delay {
+			return Err(format!("to_self_delay of {} blocks is more than our maximum of {}", msg.to_self_delay, policy.max_to_self_delay));
+		}
+		if let Some(max) = policy.max_pending_per_peer {
+			let pending = self.channel_manager.list_channels().iter()
+				.filter(|chan| chan.remote_network_id == *their_node_id && chan.short_channel_id.is_none())
+				.count();
+			if pending >= max {
+				return Err(format!("already have {} unconfirmed channels with this peer", pending));
+			}
+		}
+		if let Some(max) = policy.max_total_capacity_satoshis {
+			// This includes channels which are still being opened.
+			let total_capacity: u64 = self.channel_manager.list_channels().iter().map(|chan| chan.channel_value_satoshis).sum();
+			if total_capacity.saturating_add(msg.funding_satoshis) > max {
+				return Err(format!("it would bring our total channel capacity above our maximum of {} sat", max));
+			}
+		}
+		Ok(())
+	}
+}
+impl MessageSendEventsProvider for PeerFilter {
+	fn get_and_clear_pending_msg_events(&self) -> Vec<MessageSendEvent> {
+		let mut events = Vec::new();
+		std::mem::swap(&mut events, &mut *self.pending_msg_events.lock().unwrap());
+		events.append(&mut self.channel_manager.get_and_clear_pending_msg_events());
+		events
+	}
+}
+impl msgs::ChannelMessageHandler for PeerFilter {
+	fn handle_open_channel(&self, their_node_id: &PublicKey, their_features: InitFeatures, msg: &msgs::OpenChannel) {
+		if !self.allowed(their_node_id) { return; }
+		match self.check_open_channel(their_node_id, msg) {
+			Ok(()) => self.channel_manager.handle_open_channel(their_node_id, their_features, msg),
+			Err(e) => {
+				println!("Rejected inbound channel of {} sat from {}: {}", msg.funding_satoshis, hex_str(&their_node_id.serialize()), e);
+				self.pending_msg_events.lock().unwrap().push(MessageSendEvent::HandleError {
+					node_id: *their_node_id,
+					action: msgs::ErrorAction::SendErrorMessage {
+						msg: msgs::ErrorMessage { channel_id: msg.temporary_channel_id, data: e },
+					},
+				});
+			},
+		}
+	}
+	fn handle_accept_channel(&self, their_node_id: &PublicKey, their_features: InitFeatures, msg: &msgs::AcceptChannel) {
+		if self.allowed(their_node_id) { self.channel_manager.handle_accept_channel(their_node_id, their_features, msg); }
+	}
+	fn handle_funding_created(&self, their_node_id: &PublicKey, msg: &msgs::FundingCreated) {
+		if self.allowed(their_node_id) { self.channel_manager.handle_funding_created(their_node_id, msg); }
+	}
+	fn handle_funding_signed(&self, their_node_id: &PublicKey, msg: &msgs::FundingSigned) {
+		if self.allowed(their_node_id) { self.channel_manager.handle_funding_signed(their_node_id, msg); }
+	}
+	fn handle_funding_locked(&self, their_node_id: &PublicKey, msg: &msgs::FundingLocked) {
+		if self.allowed(their_node_id) { self.channel_manager.handle_funding_locked(their_node_id, msg); }
+	}
+	fn handle_shutdown(&self, their_node_id: &PublicKey, msg: &msgs::Shutdown) {
+		if self.allowed(their_node_id) { self.channel_manager.handle_shutdown(their_node_id, msg); }
+	}
+	fn handle_closing_signed(&self, their_node_id: &PublicKey, msg: &msgs::ClosingSigned) {
+		if self.allowed(their_node_id) { self.channel_manager.handle_closing_signed(their_node_id, msg); }
+	}
+	fn handle_update_add_htlc(&self, their_node_id: &PublicKey, msg: &msgs::UpdateAddHTLC) {
+		if self.allowed(their_node_id) { self.channel_manager.handle_update_add_htlc(their_node_id, msg); }
+	}
+	fn handle_update_fulfill_htlc(&self, their_node_id: &PublicKey, msg: &msgs::UpdateFulfillHTLC) {
+		if self.allowed(their_node_id) { self.channel_manager.handle_update_fulfill_htlc(their_node_id, msg); }
+	}
+	fn handle_update_fail_htlc(&self, their_node_id: &PublicKey, msg: &msgs::UpdateFailHTLC) {
+		if self.allowed(their_node_id) { self.channel_manager.handle_update_fail_htlc(their_node_id, msg); }
+	}
+	fn handle_update_fail_malformed_htlc(&self, their_node_id: &PublicKey, msg: &msgs::UpdateFailMalformedHTLC) {
+		if self.allowed(their_node_id) { self.channel_manager.handle_update_fail_malformed_htlc(their_node_id, msg); }
+	}
+	fn handle_commitment_signed(&self, their_node_id: &PublicKey, msg: &msgs::CommitmentSigned) {
+		if self.allowed(their_node_id) { self.channel_manager.handle_commitment_signed(their_node_id, msg); }
+	}
+	fn handle_revoke_and_ack(&self, their_node_id: &PublicKey, msg: &msgs::RevokeAndACK) {
+		if self.allowed(their_node_id) { self.channel_manager.handle_revoke_and_ack(their_node_id, msg); }
+	}
+	fn handle_update_fee(&self, their_node_id: &PublicKey, msg: &msgs::UpdateFee) {
+		if self.allowed(their_node_id) { self.channel_manager.handle_update_fee(their_node_id, msg); }
+	}
+	fn handle_announcement_signatures(&self, their_node_id: &PublicKey, msg: &msgs::AnnouncementSignatures) {
+		if self.allowed(their_node_id) { self.channel_manager.handle_announcement_signatures(their_node_id, msg); }
+	}
+	fn peer_disconnected(&self, their_node_id: &PublicKey, no_connection_possible: bool) {
+		self.channel_manager.peer_disconnected(their_node_id, no_connection_possible);
+	}
+	fn peer_connected(&self, their_node_id: &PublicKey, msg: &msgs::Init) {
+		if self.allowed(their_node_id) {
+			self.channel_manager.peer_connected(their_node_id, msg);
+		} else {
+			println!("Disconnecting peer {}, it is not allowed by our peers policy", hex_str(&their_node_id.serialize()));
+			self.pending_msg_events.lock().unwrap().push(MessageSendEvent::HandleError {
+				node_id: *their_node_id,
+				action: msgs::ErrorAction::DisconnectPeer { msg: None },
+			});
+		}
+	}
+	fn handle_channel_reestablish(&self, their_node_id: &PublicKey, msg: &msgs::ChannelReestablish) {
+		if !self.allowed(their_node_id) { return; }
+		if let (Some(points), msgs::OptionalField::Present(data_loss_protect)) = (&self.recovery_points, &msg.data_loss_protect) {
+			let _ = points.send((msg.channel_id, data_loss_protect.my_current_per_commitment_point));
+		}
+		self.channel_manager.handle_channel_reestablish(their_node_id, msg);
+	}
+	fn handle_error(&self, their_node_id: &PublicKey, msg: &msgs::ErrorMessage) {
+		if self.allowed(their_node_id) { self.channel_manager.handle_error(their_node_id, msg); }
+	}
+}
@@ -0,0 +1,552 @@
+use lightning::chain::transaction::OutPoint;
+
+use bitcoin::hashes::Hash;
+use bitcoin::hashes::hex::{ToHex, FromHex};
+use bitcoin::hashes::sha256::Hash as Sha256Hash;
+use bitcoin::hash_types::Txid;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// fsync()s the directory containing path, which is required (at least on Linux) for a rename()
+/// or newly-created file to survive power loss.
+pub fn fsync_parent_dir(path: &str) -> Result<(), std::io::Error> {
+	let parent = match Path::new(path).parent() {
+		Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+		Some(parent) => parent,
+		None => Path::new("."),
+	};
+	fs::File::open(parent)?.sync_all()
+}
+
+/// Writes filename via a temporary file such that when we return Ok the new contents (and the
+/// directory entry pointing to them) are on disk, and a crash at any point leaves either the old
+/// or the new contents in place, never a partial write.
+pub fn write_durably<F: FnOnce(&mut BufWriter<fs::File>) -> Result<(), std::io::Error>>(filename: &str, write: F) -> Result<(), std::io::Error> {
+	let tmp_filename = filename.to_string() + ".tmp";
+	{
+		let f = fs::File::create(&tmp_filename)?;
+		let mut writer = BufWriter::new(f);
+		write(&mut writer)?;
+		writer.flush()?;
+		writer.get_ref().sync_all()?;
+	}
+	fs::rename(&tmp_filename, filename)?;
+	fsync_parent_dir(filename)
+}
+
+/// Monitors are stored behind this magic and a SHA256 of their serialization, so that a corrupt
+/// monitor can be told apart from one we merely failed to deserialize. Monitors written before we
+/// added the checksum have no magic and are read as-is.
+const MONITOR_MAGIC: &[u8; 8] = b"LNMONv1\0";
+
+pub fn seal_monitor(data: &[u8]) -> Vec<u8> {
+	let mut res = Vec::with_capacity(MONITOR_MAGIC.len() + 32 + data.len());
+	res.extend_from_slice(MONITOR_MAGIC);
+	res.extend_from_slice(&Sha256Hash::hash(data)[..]);
+	res.extend_from_slice(data);
+	res
+}
+
+/// Checks the checksum of a stored monitor, returning its serialization.
+pub fn unseal_monitor(data: &[u8]) -> Result<&[u8], String> {
+	if data.len() < MONITOR_MAGIC.len() || &data[..MONITOR_MAGIC.len()] != MONITOR_MAGIC {
+		return Ok(data);
+	}
+	if data.len() < MONITOR_MAGIC.len() + 32 {
+		return Err(format!("truncated to {} bytes", data.len()));
+	}
+	let (checksum, monitor) = data[MONITOR_MAGIC.len()..].split_at(32);
+	if &Sha256Hash::hash(monitor)[..] != checksum {
+		return Err("checksum mismatch".to_string());
+	}
+	Ok(monitor)
+}
+
+/// The objects which make up a node's state.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StorageKey {
+	KeySeed,
+	ChannelManager,
+	Router,
+	ChannelBackup,
+	/// When we first saw the funding output of each closed channel spent, see MonitorArchiver.
+	ArchiveState,
+	/// Where we've reached each peer, see PeerAddressBook.
+	PeerAddresses,
+	/// The forwarding fee set with setfees, see read_fee_policy.
+	FeePolicy,
+	Monitor(OutPoint),
+	/// The monitor of a channel which has been closed and fully resolved, kept only for reference.
+	ArchivedMonitor(OutPoint),
+}
+impl StorageKey {
+	/// The name of the object, which is also its path relative to the storage directory.
+	pub fn name(&self) -> String {
+		match self {
+			StorageKey::KeySeed => "key_seed".to_string(),
+			StorageKey::ChannelManager => "manager_data".to_string(),
+			StorageKey::Router => "router_data".to_string(),
+			StorageKey::ChannelBackup => "channel_backup".to_string(),
+			StorageKey::ArchiveState => "archive_state".to_string(),
+			StorageKey::PeerAddresses => "peer_addresses".to_string(),
+			StorageKey::FeePolicy => "fee_policy".to_string(),
+			StorageKey::Monitor(funding_txo) => format!("monitors/{}_{}", funding_txo.txid.to_hex(), funding_txo.index),
+			StorageKey::ArchivedMonitor(funding_txo) => format!("archive/{}_{}", funding_txo.txid.to_hex(), funding_txo.index),
+		}
+	}
+
+	/// Parses a monitor file name of the form txid_index (ie without the monitors/ prefix).
+	pub fn monitor_from_filename(filename: &str) -> Option<OutPoint> {
+		if !filename.is_ascii() || filename.len() <= 65 || filename.as_bytes()[64] != '_' as u8 { return None; }
+		let txid = Txid::from_hex(filename.split_at(64).0).ok()?;
+		let index = filename.split_at(65).1.parse().ok()?;
+		Some(OutPoint { txid, index })
+	}
+}
+
+/// A backend which can durably store our node's state. Writes must not return until the data is
+/// safely persisted, as we tell our counterparties about channel updates only after monitors are
+/// written.
+pub trait NodeStore: Send + Sync {
+	/// Reads an object, returning Ok(None) if it has never been written.
+	fn read(&self, key: &StorageKey) -> Result<Option<Vec<u8>>, std::io::Error>;
+	fn write(&self, key: &StorageKey, data: &[u8]) -> Result<(), std::io::Error>;
+	fn delete(&self, key: &StorageKey) -> Result<(), std::io::Error>;
+	/// Lists the funding outpoints of all stored channel monitors.
+	fn list_monitors(&self) -> Result<Vec<OutPoint>, std::io::Error>;
+	/// Reads the copy of a monitor made while it was being overwritten, if a write was interrupted
+	/// and left one behind.
+	fn read_monitor_backup(&self, _funding_txo: &OutPoint) -> Result<Option<Vec<u8>>, std::io::Error> {
+		Ok(None)
+	}
+	/// Replaces a monitor with its .bk copy, once we know the monitor itself is unusable. Unlike
+	/// write(), this must not copy the unusable monitor over the .bk copy first.
+	fn restore_monitor_backup(&self, _funding_txo: &OutPoint) -> Result<(), std::io::Error> {
+		Err(std::io::Error::new(std::io::ErrorKind::NotFound, "no .bk copy to restore"))
+	}
+	/// Lists the funding outpoints of all archived channel monitors.
+	fn list_archived_monitors(&self) -> Result<Vec<OutPoint>, std::io::Error>;
+
+	/// Moves a monitor to the archive, after which we no longer load it on startup.
+	fn archive_monitor(&self, funding_txo: &OutPoint) -> Result<(), std::io::Error> {
+		let data = match self.read(&StorageKey::Monitor(*funding_txo))? {
+			Some(data) => data,
+			None => return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "no monitor to archive")),
+		};
+		self.write(&StorageKey::ArchivedMonitor(*funding_txo), &data)?;
+		self.delete(&StorageKey::Monitor(*funding_txo))
+	}
+}
+
+/// Stores everything as individual files in a directory, with monitors in a monitors/
+/// subdirectory.
+pub struct FilesystemStore {
+	path: String,
+}
+impl FilesystemStore {
+	pub fn new(path: String) -> Result<Self, std::io::Error> {
+		if !fs::metadata(&path)?.is_dir() {
+			return Err(std::io::Error::new(std::io::ErrorKind::Other, "storage_directory_path must be a directory (or symlink to one)"));
+		}
+		for dir in ["/monitors", "/archive"].iter() {
+			match fs::create_dir(path.clone() + dir) {
+				Ok(()) => {},
+				Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => {},
+				Err(e) => return Err(e),
+			}
+		}
+		Ok(Self { path })
+	}
+
+	fn filename(&self, key: &StorageKey) -> String {
+		format!("{}/{}", self.path, key.name())
+	}
+
+	fn write_monitor(&self, filename: &str, data: &[u8]) -> Result<(), std::io::Error> {
+		// Do a crazy dance with lots of fsync()s to be overly cautious here...
+		// We never want to end up in a state where we've lost the old data, or end up using the
+		// old data on power loss after we've returned
+		// Note that fsync()ing the files themselves isn't enough (at least on Linux), we also need
+		// to fsync the containing dir after every rename/create for the new entry to stick.
+		let tmp_filename = filename.to_string() + ".tmp";
+		{
+			let mut f = fs::File::create(&tmp_filename)?;
+			f.write_all(data)?;
+			f.sync_all()?;
+		}
+		// We don't need to create a backup if didn't already have the file, but in any other case
+		// try to create the backup and expect failure on fs::copy() if eg there's a perms issue.
+		let need_bk = match fs::metadata(filename) {
+			Ok(data) => {
+				if !data.is_file() { return Err(std::io::Error::new(std::io::ErrorKind::Other, "monitor path is not a file")); }
+				true
+			},
+			Err(e) => match e.kind() {
+				std::io::ErrorKind::NotFound => false,
+				_ => true,
+			}
+		};
+		let bk_filename = filename.to_string() + ".bk";
+		if need_bk {
+			fs::copy(filename, &bk_filename)?;
+			fs::File::open(&bk_filename)?.sync_all()?;
+			fsync_parent_dir(&bk_filename)?;
+		}
+		fs::rename(&tmp_filename, filename)?;
+		fs::File::open(filename)?.sync_all()?;
+		fsync_parent_dir(filename)?;
+		if need_bk {
+			fs::remove_file(&bk_filename)?;
+			fsync_parent_dir(&bk_filename)?;
+		}
+		Ok(())
+	}
+}
+impl NodeStore for FilesystemStore {
+	fn read(&self, key: &StorageKey) -> Result<Option<Vec<u8>>, std::io::Error> {
+		match fs::read(self.filename(key)) {
+			Ok(data) => Ok(Some(data)),
+			Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+			Err(e) => Err(e),
+		}
+	}
+
+	fn write(&self, key: &StorageKey, data: &[u8]) -> Result<(), std::io::Error> {
+		match key {
+			StorageKey::Monitor(_) => self.write_monitor(&self.filename(key), data),
+			_ => write_durably(&self.filename(key), |writer| writer.write_all(data)),
+		}
+	}
+
+	fn delete(&self, key: &StorageKey) -> Result<(), std::io::Error> {
+		let filename = self.filename(key);
+		fs::remove_file(&filename)?;
+		if let StorageKey::Monitor(_) = key {
+			// Otherwise list_monitors() would resurrect the monitor from its .bk copy.
+			match fs::remove_file(filename.clone() + ".bk") {
+				Ok(()) => {},
+				Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {},
+				Err(e) => return Err(e),
+			}
+		}
+		fsync_parent_dir(&filename)
+	}
+
+	/// Also lists monitors for which only a .bk copy exists, so that we try to recover them.
+	fn list_monitors(&self) -> Result<Vec<OutPoint>, std::io::Error> {
+		let mut res = Vec::new();
+		for file_option in fs::read_dir(self.path.clone() + "/monitors")? {
+			let file = file_option?;
+			let filename = match file.file_name().into_string() {
+				Ok(filename) => filename,
+				Err(filename) => {
+					println!("WARNING: Ignoring unrecognized file {:?} in {}/monitors", filename, self.path);
+					continue;
+				},
+			};
+			// A .tmp file is a write which never completed, and thus never let its channel make
+			// progress, so the previous version is still the one we need.
+			if filename.ends_with(".tmp") { continue; }
+			let base = if filename.ends_with(".bk") { &filename[..filename.len() - 3] } else { &filename[..] };
+			match StorageKey::monitor_from_filename(base) {
+				Some(funding_txo) => if !res.contains(&funding_txo) { res.push(funding_txo) },
+				None => println!("WARNING: Ignoring unrecognized file {} in {}/monitors", filename, self.path),
+			}
+		}
+		Ok(res)
+	}
+
+	fn list_archived_monitors(&self) -> Result<Vec<OutPoint>, std::io::Error> {
+		let mut res = Vec::new();
+		for file_option in fs::read_dir(self.path.clone() + "/archive")? {
+			let file = file_option?;
+			if let Some(funding_txo) = file.file_name().to_str().and_then(StorageKey::monitor_from_filename) {
+				res.push(funding_txo);
+			}
+		}
+		Ok(res)
+	}
+
+	fn read_monitor_backup(&self, funding_txo: &OutPoint) -> Result<Option<Vec<u8>>, std::io::Error> {
+		match fs::read(self.filename(&StorageKey::Monitor(*funding_txo)) + ".bk") {
+			Ok(data) => Ok(Some(data)),
+			Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+			Err(e) => Err(e),
+		}
+	}
+
+	fn restore_monitor_backup(&self, funding_txo: &OutPoint) -> Result<(), std::io::Error> {
+		let filename = self.filename(&StorageKey::Monitor(*funding_txo));
+		fs::rename(filename.clone() + ".bk", &filename)?;
+		fsync_parent_dir(&filename)
+	}
+}
+
+/// Keeps everything in memory, useful for tests and throwaway regtest nodes. Everything is lost
+/// on restart!
+pub struct MemoryStore {
+	objects: Mutex<HashMap<StorageKey, Vec<u8>>>,
+}
+impl MemoryStore {
+	pub fn new() -> Self {
+		Self { objects: Mutex::new(HashMap::new()) }
+	}
+}
+impl NodeStore for MemoryStore {
+	fn read(&self, key: &StorageKey) -> Result<Option<Vec<u8>>, std::io::Error> {
+		Ok(self.objects.lock().unwrap().get(key).cloned())
+	}
+
+	fn write(&self, key: &StorageKey, data: &[u8]) -> Result<(), std::io::Error> {
+		self.objects.lock().unwrap().insert(*key, data.to_vec());
+		Ok(())
+	}
+
+	fn delete(&self, key: &StorageKey) -> Result<(), std::io::Error> {
+		self.objects.lock().unwrap().remove(key);
+		Ok(())
+	}
+
+	fn list_monitors(&self) -> Result<Vec<OutPoint>, std::io::Error> {
+		Ok(self.objects.lock().unwrap().keys().filter_map(|key| match key {
+			StorageKey::Monitor(funding_txo) => Some(*funding_txo),
+			_ => None,
+		}).collect())
+	}
+
+	fn list_archived_monitors(&self) -> Result<Vec<OutPoint>, std::io::Error> {
+		Ok(self.objects.lock().unwrap().keys().filter_map(|key| match key {
+			StorageKey::ArchivedMonitor(funding_txo) => Some(*funding_txo),
+			_ => None,
+		}).collect())
+	}
+}
+
+/// Stores everything as rows in a single SQLite table, keyed by StorageKey::name(), giving us
+/// transactional writes without any of the rename dance above.
+#[cfg(feature = "sqlite")]
+pub struct SqliteStore {
+	conn: Mutex<rusqlite::Connection>,
+}
+#[cfg(feature = "sqlite")]
+fn sqlite_err(e: rusqlite::Error) -> std::io::Error {
+	std::io::Error::new(std::io::ErrorKind::Other, e)
+}
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+	pub fn open(path: &str) -> Result<Self, std::io::Error> {
+		let conn = rusqlite::Connection::open(path).map_err(sqlite_err)?;
+		conn.execute_batch("PRAGMA journal_mode = WAL;
+			PRAGMA synchronous = FULL;
+			CREATE TABLE IF NOT EXISTS node_data (key TEXT PRIMARY KEY NOT NULL, value BLOB NOT NULL);").map_err(sqlite_err)?;
+		Ok(Self { conn: Mutex::new(conn) })
+	}
+
+	fn list_prefix(&self, prefix: &str) -> Result<Vec<OutPoint>, std::io::Error> {
+		let conn = self.conn.lock().unwrap();
+		let mut stmt = conn.prepare("SELECT key FROM node_data WHERE key LIKE ?1").map_err(sqlite_err)?;
+		let mut res = Vec::new();
+		for name in stmt.query_map(rusqlite::params![prefix.to_string() + "%"], |row| row.get::<_, String>(0)).map_err(sqlite_err)? {
+			match StorageKey::monitor_from_filename(&name.map_err(sqlite_err)?[prefix.len()..]) {
+				Some(funding_txo) => res.push(funding_txo),
+				None => println!("WARNING: Failed to parse one of the channel monitor keys in the database!"),
+			}
+		}
+		Ok(res)
+	}
+}
+#[cfg(feature = "sqlite")]
+impl NodeStore for SqliteStore {
+	fn read(&self, key: &StorageKey) -> Result<Option<Vec<u8>>, std::io::Error> {
+		use rusqlite::OptionalExtension;
+		self.conn.lock().unwrap().query_row("SELECT value FROM node_data WHERE key = ?1", rusqlite::params![key.name()], |row| row.get(0))
+			.optional().map_err(sqlite_err)
+	}
+
+	fn write(&self, key: &StorageKey, data: &[u8]) -> Result<(), std::io::Error> {
+		self.conn.lock().unwrap().execute("INSERT OR REPLACE INTO node_data (key, value) VALUES (?1, ?2)", rusqlite::params![key.name(), data])
+			.map_err(sqlite_err)?;
+		Ok(())
+	}
+
+	fn delete(&self, key: &StorageKey) -> Result<(), std::io::Error> {
+		self.conn.lock().unwrap().execute("DELETE FROM node_data WHERE key = ?1", rusqlite::params![key.name()])
+			.map_err(sqlite_err)?;
+		Ok(())
+	}
+
+	fn list_monitors(&self) -> Result<Vec<OutPoint>, std::io::Error> {
+		self.list_prefix("monitors/")
+	}
+
+	fn list_archived_monitors(&self) -> Result<Vec<OutPoint>, std::io::Error> {
+		self.list_prefix("archive/")
+	}
+}
+
+/// The directory our state lives in, if any, for storage_path as given to open_store.
+pub fn storage_directory(storage_path: &str) -> Option<&str> {
+	if storage_path == "memory:" {
+		None
+	} else if storage_path.starts_with("sqlite:") {
+		Some(&storage_path["sqlite:".len()..])
+	} else {
+		Some(storage_path)
+	}
+}
+
+/// Opens the store described by storage_path, which is either a plain directory,
+/// "sqlite:<directory>" to keep everything in <directory>/node.sqlite3, or "memory:" to persist
+/// nothing at all.
+pub fn open_store(storage_path: &str) -> Result<Arc<dyn NodeStore>, std::io::Error> {
+	if storage_path.starts_with("sqlite:") {
+		#[cfg(feature = "sqlite")]
+		return Ok(Arc::new(SqliteStore::open(&format!("{}/node.sqlite3", &storage_path["sqlite:".len()..]))?));
+		#[cfg(not(feature = "sqlite"))]
+		return Err(std::io::Error::new(std::io::ErrorKind::Other, "SQLite storage requires building with --features sqlite"));
+	}
+	if storage_path == "memory:" {
+		println!("WARNING: Using in-memory storage, all channel state will be lost on exit!");
+		return Ok(Arc::new(MemoryStore::new()));
+	}
+	Ok(Arc::new(FilesystemStore::new(storage_path.to_string())?))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn outpoint(n: u8) -> OutPoint {
+		OutPoint { txid: Txid::from_slice(&[n; 32]).unwrap(), index: n as u16 }
+	}
+
+	fn sorted(mut outpoints: Vec<OutPoint>) -> Vec<OutPoint> {
+		outpoints.sort_by_key(|outpoint| (outpoint.txid, outpoint.index));
+		outpoints
+	}
+
+	/// A fresh directory under the system temp dir, removed on drop.
+	struct TempDir(String);
+	impl TempDir {
+		fn new(name: &str) -> Self {
+			let path = format!("{}/rust-lightning-bitcoinrpc-test-{}-{}", std::env::temp_dir().display(), name, std::process::id());
+			let _ = fs::remove_dir_all(&path);
+			fs::create_dir_all(&path).unwrap();
+			TempDir(path)
+		}
+	}
+	impl Drop for TempDir {
+		fn drop(&mut self) {
+			let _ = fs::remove_dir_all(&self.0);
+		}
+	}
+
+	fn check_round_trip(store: &dyn NodeStore) {
+		assert!(store.read(&StorageKey::KeySeed).unwrap().is_none());
+		store.write(&StorageKey::KeySeed, &[1; 32]).unwrap();
+		assert_eq!(store.read(&StorageKey::KeySeed).unwrap(), Some(vec![1; 32]));
+		store.write(&StorageKey::KeySeed, &[2; 32]).unwrap();
+		assert_eq!(store.read(&StorageKey::KeySeed).unwrap(), Some(vec![2; 32]));
+		assert!(store.read(&StorageKey::ChannelManager).unwrap().is_none());
+
+		assert!(store.list_monitors().unwrap().is_empty());
+		store.write(&StorageKey::Monitor(outpoint(1)), &[1, 2, 3]).unwrap();
+		store.write(&StorageKey::Monitor(outpoint(2)), &[4, 5, 6]).unwrap();
+		store.write(&StorageKey::Monitor(outpoint(2)), &[7, 8, 9]).unwrap();
+		assert_eq!(sorted(store.list_monitors().unwrap()), vec![outpoint(1), outpoint(2)]);
+		assert_eq!(store.read(&StorageKey::Monitor(outpoint(2))).unwrap(), Some(vec![7, 8, 9]));
+		// Completed writes never leave a backup copy behind.
+		assert!(store.read_monitor_backup(&outpoint(2)).unwrap().is_none());
+
+		store.archive_monitor(&outpoint(1)).unwrap();
+		assert_eq!(store.list_monitors().unwrap(), vec![outpoint(2)]);
+		assert_eq!(store.list_archived_monitors().unwrap(), vec![outpoint(1)]);
+		assert_eq!(store.read(&StorageKey::ArchivedMonitor(outpoint(1))).unwrap(), Some(vec![1, 2, 3]));
+		assert!(store.read(&StorageKey::Monitor(outpoint(1))).unwrap().is_none());
+
+		store.delete(&StorageKey::Monitor(outpoint(2))).unwrap();
+		assert!(store.list_monitors().unwrap().is_empty());
+	}
+
+	#[test]
+	fn memory_store_round_trip() {
+		check_round_trip(&MemoryStore::new());
+	}
+
+	#[test]
+	fn filesystem_store_round_trip() {
+		let dir = TempDir::new("round-trip");
+		check_round_trip(&FilesystemStore::new(dir.0.clone()).unwrap());
+	}
+
+	#[cfg(feature = "sqlite")]
+	#[test]
+	fn sqlite_store_round_trip() {
+		let dir = TempDir::new("sqlite");
+		check_round_trip(&SqliteStore::open(&format!("{}/node.sqlite3", dir.0)).unwrap());
+	}
+
+	#[test]
+	fn filesystem_store_interrupted_writes() {
+		let dir = TempDir::new("interrupted");
+		let store = FilesystemStore::new(dir.0.clone()).unwrap();
+		store.write(&StorageKey::Monitor(outpoint(1)), &[1]).unwrap();
+
+		// A write which never got as far as the rename leaves only a .tmp file, which is ignored.
+		let tmp_name = format!("{}/{}.tmp", dir.0, StorageKey::Monitor(outpoint(2)).name());
+		fs::write(&tmp_name, &[2]).unwrap();
+		assert_eq!(store.list_monitors().unwrap(), vec![outpoint(1)]);
+
+		// One interrupted after the old version was copied aside leaves a .bk file, which is
+		// listed so that it can be recovered.
+		let bk_name = format!("{}/{}.bk", dir.0, StorageKey::Monitor(outpoint(3)).name());
+		fs::write(&bk_name, &[3]).unwrap();
+		assert_eq!(sorted(store.list_monitors().unwrap()), vec![outpoint(1), outpoint(3)]);
+		assert!(store.read(&StorageKey::Monitor(outpoint(3))).unwrap().is_none());
+		assert_eq!(store.read_monitor_backup(&outpoint(3)).unwrap(), Some(vec![3]));
+
+		// The next write of the monitor replaces the .tmp file.
+		store.write(&StorageKey::Monitor(outpoint(2)), &[4]).unwrap();
+		assert!(fs::metadata(&tmp_name).is_err());
+		assert_eq!(store.read(&StorageKey::Monitor(outpoint(2))).unwrap(), Some(vec![4]));
+
+		// Deleting a monitor removes its .bk copy too, so it isn't resurrected.
+		fs::write(format!("{}/{}", dir.0, StorageKey::Monitor(outpoint(3)).name()), &[5]).unwrap();
+		store.delete(&StorageKey::Monitor(outpoint(3))).unwrap();
+		assert!(fs::metadata(&bk_name).is_err());
+		assert_eq!(sorted(store.list_monitors().unwrap()), vec![outpoint(1), outpoint(2)]);
+
+		// Restoring a .bk copy over a corrupt monitor moves it into place, rather than first
+		// copying the corrupt monitor over it as a write would.
+		let monitor_name = format!("{}/{}", dir.0, StorageKey::Monitor(outpoint(2)).name());
+		fs::write(monitor_name.clone() + ".bk", &[6]).unwrap();
+		store.restore_monitor_backup(&outpoint(2)).unwrap();
+		assert_eq!(store.read(&StorageKey::Monitor(outpoint(2))).unwrap(), Some(vec![6]));
+		assert!(store.read_monitor_backup(&outpoint(2)).unwrap().is_none());
+		assert!(store.restore_monitor_backup(&outpoint(2)).is_err());
+	}
+
+	#[test]
+	fn memory_store_has_no_monitor_backups() {
+		let store = MemoryStore::new();
+		store.write(&StorageKey::Monitor(outpoint(1)), &[1]).unwrap();
+		store.write(&StorageKey::Monitor(outpoint(1)), &[2]).unwrap();
+		assert!(store.read_monitor_backup(&outpoint(1)).unwrap().is_none());
+	}
+
+	#[test]
+	fn sealed_monitor_checksum() {
+		let sealed = seal_monitor(&[1, 2, 3]);
+		assert_eq!(unseal_monitor(&sealed).unwrap(), &[1, 2, 3]);
+		let mut corrupt = sealed.clone();
+		*corrupt.last_mut().unwrap() ^= 1;
+		assert!(unseal_monitor(&corrupt).is_err());
+		assert!(unseal_monitor(&sealed[..MONITOR_MAGIC.len() + 10]).is_err());
+		// Monitors from before we added checksums are read as-is.
+		assert_eq!(unseal_monitor(&[4, 5, 6]).unwrap(), &[4, 5, 6]);
+	}
+}
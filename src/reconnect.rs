@@ -0,0 +1,145 @@
+use crate::chain_monitor::*;
+use crate::net::*;
+use crate::peer_filter::*;
+use crate::persist::*;
+use crate::utils::*;
+
+use lightning::ln::{router, channelmanager};
+
+use bitcoin::secp256k1::key::PublicKey;
+
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often we check for channel peers we aren't connected to.
+const RECONNECT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// After a failed reconnection attempt we wait this long before the next one, doubling each time
+/// up to MAX_RECONNECT_BACKOFF.
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// Remembers (in the store, as peer_addresses) every address we've successfully connected to
+/// each peer at.
+pub struct PeerAddressBook {
+	store: Arc<dyn NodeStore>,
+	addresses: Mutex<HashMap<PublicKey, Vec<PeerAddress>>>,
+}
+impl PeerAddressBook {
+	pub fn new(store: Arc<dyn NodeStore>) -> Self {
+		let mut addresses = HashMap::new();
+		match store.read(&StorageKey::PeerAddresses) {
+			Ok(Some(data)) => match serde_json::from_slice::<serde_json::Value>(&data) {
+				Ok(v) => if let Some(peers) = v.as_object() {
+					for (node_id, addrs) in peers.iter() {
+						let node_id = match hex_to_compressed_pubkey(node_id) {
+							Some(node_id) => node_id,
+							None => continue,
+						};
+						let addrs: Vec<PeerAddress> = addrs.as_array().map(|addrs| addrs.iter()
+							.filter_map(|addr| addr.as_str().and_then(|addr| addr.parse().ok()))
+							.collect()).unwrap_or(Vec::new());
+						addresses.insert(node_id, addrs);
+					}
+				},
+				Err(e) => println!("WARNING: Failed to parse peer_addresses, we may not be able to reconnect to some peers: {}", e),
+			},
+			Ok(None) => {},
+			Err(e) => println!("WARNING: Failed to read peer_addresses, we may not be able to reconnect to some peers: {}", e),
+		}
+		Self { store, addresses: Mutex::new(addresses) }
+	}
+
+	/// Records an address we reached node_id at, most recent first.
+	pub fn add(&self, node_id: PublicKey, addr: PeerAddress) {
+		let mut addresses = self.addresses.lock().unwrap();
+		let addrs = addresses.entry(node_id).or_insert(Vec::new());
+		if addrs.first() == Some(&addr) { return; }
+		addrs.retain(|known| *known != addr);
+		addrs.insert(0, addr);
+
+		let mut peers = serde_json::Map::new();
+		for (node_id, addrs) in addresses.iter() {
+			peers.insert(hex_str(&node_id.serialize()), serde_json::json!(addrs.iter().map(|addr| addr.to_string()).collect::<Vec<_>>()));
+		}
+		if let Err(e) = self.store.write(&StorageKey::PeerAddresses, &serde_json::to_vec(&peers).unwrap()) {
+			println!("WARNING: Failed to write peer_addresses: {}", e);
+		}
+	}
+
+	pub fn get(&self, node_id: &PublicKey) -> Vec<PeerAddress> {
+		self.addresses.lock().unwrap().get(node_id).cloned().unwrap_or(Vec::new())
+	}
+}
+
+struct Backoff {
+	next_attempt: Instant,
+	delay: Duration,
+}
+
+/// Reconnects to every peer we have a channel with whenever we aren't connected to them, trying
+/// the addresses we've reached them at before and then any they've announced.
+pub fn spawn_reconnector(peer_manager: FilteredPeerManager,
+	channel_manager: channelmanager::SimpleArcChannelManager<crate::ChannelMonitor, ChainInterface, FeeEstimator>,
+	router: Arc<router::Router>, address_book: Arc<PeerAddressBook>, event_notify: mpsc::Sender<()>, proxy: Option<SocketAddr>) -> tokio::task::JoinHandle<()>
+{
+	tokio::spawn(async move {
+		let mut backoffs: HashMap<PublicKey, Backoff> = HashMap::new();
+		// Peers we're still trying addresses for, each in its own task so that one peer's dead
+		// addresses don't hold up reconnecting to the others.
+		let attempting: Arc<Mutex<HashSet<PublicKey>>> = Arc::new(Mutex::new(HashSet::new()));
+		let mut intvl = tokio::time::interval(RECONNECT_CHECK_INTERVAL);
+		loop {
+			intvl.tick().await;
+			let connected: HashSet<PublicKey> = peer_manager.get_peer_node_ids().into_iter().collect();
+			let channel_peers: HashSet<PublicKey> = channel_manager.list_channels().iter().map(|chan| chan.remote_network_id).collect();
+			backoffs.retain(|node_id, _| channel_peers.contains(node_id) && !connected.contains(node_id));
+
+			for node_id in channel_peers.difference(&connected) {
+				if let Some(backoff) = backoffs.get(node_id) {
+					if Instant::now() < backoff.next_attempt { continue; }
+				}
+				if attempting.lock().unwrap().contains(node_id) { continue; }
+				let mut addrs = address_book.get(node_id);
+				for addr in router.get_addresses(node_id).unwrap_or(Vec::new()).iter().map(PeerAddress::from_net_address) {
+					// We can't reach .onion addresses without a proxy.
+					if !addrs.contains(&addr) && (proxy.is_some() || !addr.is_onion()) {
+						addrs.push(addr);
+					}
+				}
+				if addrs.is_empty() { continue; }
+
+				// Keep backing off until the handshake completes and the peer shows up as connected.
+				let delay = match backoffs.get(node_id) {
+					Some(backoff) => std::cmp::min(backoff.delay * 2, MAX_RECONNECT_BACKOFF),
+					None => MIN_RECONNECT_BACKOFF,
+				};
+				backoffs.insert(*node_id, Backoff { next_attempt: Instant::now() + delay, delay });
+
+				attempting.lock().unwrap().insert(*node_id);
+				let node_id = *node_id;
+				let peer_manager = peer_manager.clone();
+				let address_book = address_book.clone();
+				let event_notify = event_notify.clone();
+				let attempting = attempting.clone();
+				tokio::spawn(async move {
+					for addr in addrs {
+						match connect_outbound(peer_manager.clone(), event_notify.clone(), node_id, &addr, proxy).await {
+							Ok(_) => {
+								println!("Reconnecting to channel peer {} at {}", hex_str(&node_id.serialize()), addr);
+								address_book.add(node_id, addr);
+								break;
+							},
+							Err(e) => println!("Failed to reconnect to channel peer {} at {}: {}", hex_str(&node_id.serialize()), addr, e),
+						}
+					}
+					attempting.lock().unwrap().remove(&node_id);
+				});
+			}
+		}
+	})
+}
@@ -0,0 +1,207 @@
+use crate::persist::*;
+
+use lightning::chain::transaction::OutPoint;
+
+use bitcoin::hashes::{Hash, HashEngine, Hmac, HmacEngine};
+use bitcoin::hashes::sha256::Hash as Sha256Hash;
+
+use tokio::sync::mpsc;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Somewhere other than our main store that every channel monitor update must reach before we let
+/// the channel make progress.
+pub trait MonitorReplica: Send + Sync {
+	fn description(&self) -> String;
+	fn write_monitor(&self, funding_txo: &OutPoint, data: &[u8]) -> Result<(), std::io::Error>;
+}
+
+/// Replicates monitors into the monitors/ subdirectory of another directory, eg on a second disk.
+pub struct DirectoryReplica {
+	path: String,
+	store: FilesystemStore,
+}
+impl MonitorReplica for DirectoryReplica {
+	fn description(&self) -> String {
+		self.path.clone()
+	}
+	fn write_monitor(&self, funding_txo: &OutPoint, data: &[u8]) -> Result<(), std::io::Error> {
+		self.store.write(&StorageKey::Monitor(*funding_txo), data)
+	}
+}
+
+/// Replicates monitors to a monitor_receiver instance over TCP.
+///
+/// The receiver opens each connection by sending a random 32-byte challenge. We then send the
+/// funding txid (32 bytes), funding output index (2 bytes BE), data length (4 bytes BE), the
+/// serialized monitor, and an HMAC-SHA256 over the challenge and everything we sent, keyed with a
+/// secret shared with the receiver. The receiver replies with a single 1 byte once the monitor is
+/// durably on its disk.
+///
+/// Note that monitors are sent unencrypted, so this should only be used over a trusted network or
+/// a tunnel.
+pub struct TcpReplica {
+	host_port: String,
+	/// host_port, resolved once at startup.
+	addr: SocketAddr,
+	key: [u8; 32],
+}
+const REPLICATION_ACK: u8 = 1;
+const REPLICATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn replication_hmac(key: &[u8; 32], challenge: &[u8], header: &[u8], data: &[u8]) -> [u8; 32] {
+	let mut engine = HmacEngine::<Sha256Hash>::new(key);
+	engine.input(challenge);
+	engine.input(header);
+	engine.input(data);
+	Hmac::<Sha256Hash>::from_engine(engine).into_inner()
+}
+
+impl TcpReplica {
+	fn send_monitor(&self, funding_txo: &OutPoint, data: &[u8]) -> Result<(), std::io::Error> {
+		let mut stream = TcpStream::connect_timeout(&self.addr, REPLICATION_TIMEOUT)?;
+		stream.set_read_timeout(Some(REPLICATION_TIMEOUT))?;
+		stream.set_write_timeout(Some(REPLICATION_TIMEOUT))?;
+
+		let mut challenge = [0; 32];
+		stream.read_exact(&mut challenge)?;
+
+		let mut header = Vec::with_capacity(38);
+		header.extend_from_slice(&funding_txo.txid[..]);
+		header.extend_from_slice(&funding_txo.index.to_be_bytes());
+		header.extend_from_slice(&(data.len() as u32).to_be_bytes());
+		stream.write_all(&header)?;
+		stream.write_all(data)?;
+		stream.write_all(&replication_hmac(&self.key, &challenge, &header, data))?;
+		stream.flush()?;
+
+		let mut ack = [0; 1];
+		stream.read_exact(&mut ack)?;
+		if ack[0] != REPLICATION_ACK {
+			return Err(std::io::Error::new(std::io::ErrorKind::Other, "replica failed to store monitor"));
+		}
+		Ok(())
+	}
+}
+impl MonitorReplica for TcpReplica {
+	fn description(&self) -> String {
+		self.host_port.clone()
+	}
+	fn write_monitor(&self, funding_txo: &OutPoint, data: &[u8]) -> Result<(), std::io::Error> {
+		self.send_monitor(funding_txo, data)
+	}
+}
+
+/// Parses a --monitor-replica argument, which is either a directory path or
+/// path_to_shared_key_file@host:port for a remote monitor_receiver.
+pub fn parse_replica(spec: &str) -> Result<Box<dyn MonitorReplica>, String> {
+	let mut parts = spec.splitn(2, '@');
+	let first = parts.next().unwrap();
+	if let Some(host_port) = parts.next() {
+		let key_bytes = fs::read(first).map_err(|e| format!("Failed to read replication key file {}: {}", first, e))?;
+		if key_bytes.len() != 32 {
+			return Err(format!("Replication key file {} must contain exactly 32 bytes", first));
+		}
+		let mut key = [0; 32];
+		key.copy_from_slice(&key_bytes);
+		let addr = host_port.to_socket_addrs().ok().and_then(|mut addrs| addrs.next())
+			.ok_or_else(|| format!("Failed to resolve replica address {}", host_port))?;
+		Ok(Box::new(TcpReplica { host_port: host_port.to_string(), addr, key }))
+	} else {
+		let store = FilesystemStore::new(first.to_string()).map_err(|e| format!("Failed to open replica directory {}: {}", first, e))?;
+		Ok(Box::new(DirectoryReplica { path: first.to_string(), store }))
+	}
+}
+
+/// How long we wait before re-sending monitors which failed to replicate.
+const REPLICATION_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+struct PendingMonitors {
+	/// The latest version of each monitor which hasn't reached every replica yet, with its
+	/// update_id.
+	monitors: HashMap<OutPoint, (u64, Vec<u8>)>,
+	/// Monitors which have reached every replica since take_replicated() was last called, with the
+	/// update_id of the version they reached them at.
+	replicated: Vec<(OutPoint, u64)>,
+}
+
+/// Sends monitors to every replica on a dedicated thread, so that slow or unreachable replicas
+/// never block the async runtime. Callers pause the monitor's channel until it has been
+/// replicated, which is signalled on the receiver returned by new(), and then restore each channel
+/// returned by take_replicated() only up to the update_id which actually reached the replicas.
+pub struct Replicator {
+	pending: Arc<(Mutex<PendingMonitors>, Condvar)>,
+}
+impl Replicator {
+	pub fn new(replicas: Vec<Box<dyn MonitorReplica>>) -> (Self, mpsc::Receiver<()>) {
+		let pending = Arc::new((Mutex::new(PendingMonitors { monitors: HashMap::new(), replicated: Vec::new() }), Condvar::new()));
+		let (mut replicated_sender, replicated_receiver) = mpsc::channel(1);
+		let thread_pending = pending.clone();
+		thread::spawn(move || {
+			let (lock, condvar) = &*thread_pending;
+			let mut failing = false;
+			loop {
+				let to_send: Vec<(OutPoint, u64, Vec<u8>)> = {
+					let mut pending = lock.lock().unwrap();
+					while pending.monitors.is_empty() {
+						pending = condvar.wait(pending).unwrap();
+					}
+					pending.monitors.iter().map(|(funding_txo, (version, data))| (*funding_txo, *version, data.clone())).collect()
+				};
+				let mut all_ok = true;
+				for (funding_txo, update_id, data) in to_send {
+					let mut sent = true;
+					for replica in replicas.iter() {
+						if let Err(e) = replica.write_monitor(&funding_txo, &data) {
+							if !failing {
+								println!("WARNING: Failed to replicate channel monitor to {}, pausing channel: {}", replica.description(), e);
+							}
+							sent = false;
+						}
+					}
+					if sent {
+						let mut pending = lock.lock().unwrap();
+						// A newer version may have been queued while we were sending this one, in
+						// which case its channel must stay paused until that one is sent too.
+						if pending.monitors.get(&funding_txo).map(|(pending_update_id, _)| *pending_update_id) == Some(update_id) {
+							pending.monitors.remove(&funding_txo);
+							pending.replicated.push((funding_txo, update_id));
+							// Fails only if a notification is already waiting, which is fine.
+							let _ = replicated_sender.try_send(());
+						}
+					}
+					all_ok &= sent;
+				}
+				if !all_ok {
+					failing = true;
+					thread::sleep(REPLICATION_RETRY_INTERVAL);
+				} else if failing && lock.lock().unwrap().monitors.is_empty() {
+					println!("Monitor replicas caught up, restoring paused channels");
+					failing = false;
+				}
+			}
+		});
+		(Self { pending }, replicated_receiver)
+	}
+
+	/// Returns the monitors which have reached every replica since we were last called, with the
+	/// update_id of the version which reached them.
+	pub fn take_replicated(&self) -> Vec<(OutPoint, u64)> {
+		std::mem::replace(&mut self.pending.0.lock().unwrap().replicated, Vec::new())
+	}
+
+	/// Queues the latest version of a monitor to be sent to every replica, replacing any older
+	/// version still waiting.
+	pub fn queue(&self, funding_txo: OutPoint, update_id: u64, data: Vec<u8>) {
+		let (lock, condvar) = &*self.pending;
+		let mut pending = lock.lock().unwrap();
+		pending.monitors.insert(funding_txo, (update_id, data));
+		condvar.notify_one();
+	}
+}
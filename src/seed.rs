@@ -0,0 +1,152 @@
+use chacha20poly1305::ChaCha20Poly1305;
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::aead::generic_array::GenericArray;
+
+use rand::{thread_rng, Rng};
+
+use std::env;
+use std::fs;
+use std::io::Read;
+
+/// Encrypted seeds are stored as the magic, then scrypt's log2(N), the scrypt salt, the
+/// ChaCha20Poly1305 nonce and finally the encrypted seed with its 16-byte tag.
+const ENCRYPTED_SEED_MAGIC: &[u8; 8] = b"LNSEEDv1";
+const SCRYPT_LOG_N: u8 = 15;
+/// The largest log2(N) we'll read from a key_seed, so a corrupt or tampered file can't make us
+/// allocate gigabytes or spin for hours. At r = 8 scrypt needs 128 * r * N bytes, 256MiB here.
+const MAX_SCRYPT_LOG_N: u8 = 18;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const ENCRYPTED_SEED_LEN: usize = 8 + 1 + SALT_LEN + NONCE_LEN + 32 + 16;
+
+/// Where to get the passphrase protecting key_seed from.
+pub enum PassphraseSource {
+	/// Ask on the terminal.
+	Prompt,
+	/// Read it from the given environment variable.
+	Env(String),
+	/// Read it from the given (already open) file descriptor, eg one set up by systemd.
+	Fd(i32),
+}
+impl PassphraseSource {
+	/// Parses a --seed-passphrase= option value: "prompt", "env:VAR" or "fd:N".
+	pub fn parse(opt: &str) -> Result<Self, String> {
+		if opt == "prompt" {
+			Ok(PassphraseSource::Prompt)
+		} else if opt.starts_with("env:") {
+			Ok(PassphraseSource::Env(opt[4..].to_string()))
+		} else if opt.starts_with("fd:") {
+			opt[3..].parse().map(PassphraseSource::Fd).map_err(|_| format!("Bad file descriptor in --seed-passphrase={}", opt))
+		} else {
+			Err(format!("--seed-passphrase must be prompt, env:VAR or fd:N, not {}", opt))
+		}
+	}
+
+	pub fn read(&self, prompt: &str) -> Result<String, String> {
+		match self {
+			PassphraseSource::Prompt => rpassword::read_password_from_tty(Some(prompt)).map_err(|e| format!("Failed to read passphrase: {}", e)),
+			PassphraseSource::Env(var) => env::var(var).map_err(|_| format!("Environment variable {} with the seed passphrase is not set", var)),
+			PassphraseSource::Fd(fd) => {
+				#[cfg(unix)]
+				{
+					use std::os::unix::io::FromRawFd;
+					let mut passphrase = String::new();
+					let mut f = unsafe { fs::File::from_raw_fd(*fd) };
+					f.read_to_string(&mut passphrase).map_err(|e| format!("Failed to read passphrase from fd {}: {}", fd, e))?;
+					Ok(passphrase.trim_end_matches(|c| c == '\n' || c == '\r').to_string())
+				}
+				#[cfg(not(unix))]
+				Err(format!("Reading the passphrase from fd {} is only supported on unix", fd))
+			},
+		}
+	}
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], log_n: u8) -> Result<[u8; 32], String> {
+	let params = scrypt::ScryptParams::new(log_n, 8, 1).map_err(|_| "key_seed has invalid scrypt parameters".to_string())?;
+	let mut key = [0; 32];
+	scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut key).unwrap();
+	Ok(key)
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+	data.len() >= ENCRYPTED_SEED_MAGIC.len() && &data[..ENCRYPTED_SEED_MAGIC.len()] == ENCRYPTED_SEED_MAGIC
+}
+
+pub fn encrypt_seed(seed: &[u8; 32], passphrase: &str) -> Vec<u8> {
+	let mut salt = [0; SALT_LEN];
+	thread_rng().fill_bytes(&mut salt);
+	let mut nonce = [0; NONCE_LEN];
+	thread_rng().fill_bytes(&mut nonce);
+	let key = derive_key(passphrase, &salt, SCRYPT_LOG_N).unwrap();
+	let cipher = ChaCha20Poly1305::new(GenericArray::clone_from_slice(&key));
+	let ciphertext = cipher.encrypt(GenericArray::from_slice(&nonce), &seed[..]).expect("Encryption of 32 bytes cannot fail");
+
+	let mut res = Vec::with_capacity(ENCRYPTED_SEED_LEN);
+	res.extend_from_slice(ENCRYPTED_SEED_MAGIC);
+	res.push(SCRYPT_LOG_N);
+	res.extend_from_slice(&salt);
+	res.extend_from_slice(&nonce);
+	res.extend_from_slice(&ciphertext);
+	res
+}
+
+pub fn decrypt_seed(data: &[u8], passphrase: &str) -> Result<[u8; 32], String> {
+	if data.len() != ENCRYPTED_SEED_LEN {
+		return Err(format!("key_seed is corrupt: encrypted seeds are {} bytes, found {}", ENCRYPTED_SEED_LEN, data.len()));
+	}
+	let log_n = data[8];
+	if log_n < SCRYPT_LOG_N || log_n > MAX_SCRYPT_LOG_N {
+		return Err(format!("key_seed is corrupt: scrypt log2(N) must be between {} and {}, found {}", SCRYPT_LOG_N, MAX_SCRYPT_LOG_N, log_n));
+	}
+	let salt = &data[9..9 + SALT_LEN];
+	let nonce = &data[9 + SALT_LEN..9 + SALT_LEN + NONCE_LEN];
+	let key = derive_key(passphrase, salt, log_n)?;
+	let cipher = ChaCha20Poly1305::new(GenericArray::clone_from_slice(&key));
+	let seed = cipher.decrypt(GenericArray::from_slice(nonce), &data[9 + SALT_LEN + NONCE_LEN..])
+		.map_err(|_| "Wrong passphrase (or key_seed is corrupt)".to_string())?;
+	let mut res = [0; 32];
+	res.copy_from_slice(&seed);
+	Ok(res)
+}
+
+/// Loads a key_seed as stored on disk, decrypting it if required.
+pub fn load_seed(data: &[u8], passphrase: &PassphraseSource) -> Result<[u8; 32], String> {
+	if is_encrypted(data) {
+		decrypt_seed(data, &passphrase.read("key_seed passphrase: ")?)
+	} else if data.len() == 32 {
+		let mut seed = [0; 32];
+		seed.copy_from_slice(data);
+		Ok(seed)
+	} else {
+		Err(format!("key_seed is corrupt: expected 32 bytes (unencrypted) or {} bytes (encrypted), found {}", ENCRYPTED_SEED_LEN, data.len()))
+	}
+}
+
+/// Asks for a new passphrase twice, returning it if both match. An empty passphrase means the
+/// seed should be stored unencrypted.
+pub fn prompt_new_passphrase() -> Result<String, String> {
+	let passphrase = PassphraseSource::Prompt.read("New key_seed passphrase (empty to store unencrypted): ")?;
+	if PassphraseSource::Prompt.read("Repeat new passphrase: ")? != passphrase {
+		return Err("Passphrases didn't match".to_string());
+	}
+	Ok(passphrase)
+}
+
+/// Encodes the 32-byte node seed as a 24-word BIP39 mnemonic for paper backups. Note that the seed
+/// is used as the mnemonic's entropy directly (it is not passed through BIP39's PBKDF2), so other
+/// wallets won't derive the same keys from these words.
+pub fn seed_to_mnemonic(seed: &[u8; 32]) -> String {
+	bip39::Mnemonic::from_entropy(&seed[..]).expect("32 bytes is a valid BIP39 entropy length").to_string()
+}
+
+pub fn mnemonic_to_seed(words: &str) -> Result<[u8; 32], String> {
+	let mnemonic = bip39::Mnemonic::parse(words.trim().to_lowercase()).map_err(|e| format!("Invalid mnemonic: {}", e))?;
+	let entropy = mnemonic.to_entropy();
+	if entropy.len() != 32 {
+		return Err(format!("Node seed mnemonics must be 24 words, got {}", words.split_whitespace().count()));
+	}
+	let mut seed = [0; 32];
+	seed.copy_from_slice(&entropy);
+	Ok(seed)
+}
@@ -0,0 +1,95 @@
+use crate::commands::*;
+use crate::utils::*;
+
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+
+use std::sync::{mpsc, Arc};
+
+/// Tab-completes command names, and the node ids and channel ids commands take.
+struct ShellHelper {
+	handler: Arc<CommandHandler>,
+}
+impl ShellHelper {
+	fn node_ids(&self) -> Vec<String> {
+		let mut node_ids: Vec<String> = self.handler.peer_manager.get_peer_node_ids().iter()
+			.chain(self.handler.channel_manager.list_channels().iter().map(|chan| &chan.remote_network_id))
+			.map(|node_id| hex_str(&node_id.serialize()))
+			.collect();
+		node_ids.sort_unstable();
+		node_ids.dedup();
+		node_ids
+	}
+
+	fn channel_ids(&self) -> Vec<String> {
+		self.handler.channel_manager.list_channels().iter().map(|chan| hex_str(&chan.channel_id[..])).collect()
+	}
+}
+impl Completer for ShellHelper {
+	type Candidate = String;
+	fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+		let line = &line[..pos];
+		let start = line.rfind(' ').map(|idx| idx + 1).unwrap_or(0);
+		let words: Vec<&str> = line[..start].split_whitespace().collect();
+		let candidates = if words.is_empty() {
+			COMMANDS.iter().map(|command| command.name.to_string()).collect()
+		} else {
+			match find_command(&words) {
+				Some((command, args)) if args.is_empty() => match command.first_arg {
+					ArgKind::NodeId => self.node_ids(),
+					ArgKind::ChannelId => self.channel_ids(),
+					ArgKind::Command => COMMANDS.iter().map(|command| command.name.to_string()).collect(),
+					ArgKind::None => Vec::new(),
+				},
+				_ => Vec::new(),
+			}
+		};
+		Ok((start, candidates.into_iter().filter(|candidate| candidate.starts_with(&line[start..])).collect()))
+	}
+}
+impl Hinter for ShellHelper {}
+impl Highlighter for ShellHelper {}
+impl Validator for ShellHelper {}
+impl Helper for ShellHelper {}
+
+/// Reads commands from the terminal until it is closed (or interrupted), keeping history in
+/// history_path if given. Blocks, so should be run outside of the async runtime's workers, with
+/// runtime used to run the commands themselves.
+pub fn run_shell(handler: Arc<CommandHandler>, history_path: Option<String>, runtime: tokio::runtime::Handle) {
+	let mut editor = Editor::<ShellHelper>::new();
+	editor.set_helper(Some(ShellHelper { handler: handler.clone() }));
+	if let Some(path) = &history_path {
+		// Fails if there's no history yet, which is fine.
+		let _ = editor.load_history(path);
+	}
+	loop {
+		match editor.readline("> ") {
+			Ok(line) => {
+				if line.trim().is_empty() { continue; }
+				editor.add_history_entry(line.as_str());
+				let (output_sender, output_receiver) = mpsc::channel();
+				let command_handler = handler.clone();
+				runtime.spawn(async move {
+					let _ = output_sender.send(command_handler.run_command(&line, true).await);
+				});
+				if let Ok(output) = output_receiver.recv() {
+					print!("{}", output);
+				}
+				if let Some(path) = &history_path {
+					if let Err(e) = editor.save_history(path) {
+						println!("WARNING: Failed to save shell history to {}: {}", path, e);
+					}
+				}
+			},
+			Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+			Err(e) => {
+				println!("Failed to read command: {}", e);
+				break;
+			},
+		}
+	}
+}
@@ -1,3 +1,4 @@
+use bitcoin::network::constants::Network;
 use bitcoin::secp256k1::key::PublicKey;
 
 pub fn hex_to_vec(hex: &str) -> Option<Vec<u8>> {
@@ -22,7 +23,8 @@ pub fn hex_to_vec(hex: &str) -> Option<Vec<u8>> {
 }
 
 pub fn hex_to_compressed_pubkey(hex: &str) -> Option<PublicKey> {
-	let data = match hex_to_vec(&hex[0..33*2]) {
+	if hex.len() != 33*2 { return None; }
+	let data = match hex_to_vec(hex) {
 		Some(bytes) => bytes,
 		None => return None
 	};
@@ -52,3 +54,25 @@ pub fn slice_to_be64(v: &[u8]) -> u64 {
 	((v[6] as u64) << 8*1) |
 	((v[7] as u64) << 8*0)
 }
+
+/// Maps bitcoind's getblockchaininfo chain name to a Network.
+pub fn chain_to_network(chain: &str) -> Result<Network, String> {
+	match chain {
+		"main" => Ok(Network::Bitcoin),
+		"test" => Ok(Network::Testnet),
+		"regtest" => Ok(Network::Regtest),
+		// rust-bitcoin 0.23 (and thus rust-lightning and lightning-invoice) has no signet Network,
+		// so we have no way to get its chain hash for our channel messages, its address encoding or
+		// its invoice prefix until we upgrade.
+		"signet" => Err("bitcoind is running signet, which isn't supported by the rust-lightning version this node is built on".to_string()),
+		_ => Err(format!("bitcoind is running unknown chain {}", chain)),
+	}
+}
+
+pub fn network_to_bech32(network: Network) -> bitcoin_bech32::constants::Network {
+	match network {
+		Network::Bitcoin => bitcoin_bech32::constants::Network::Bitcoin,
+		Network::Testnet => bitcoin_bech32::constants::Network::Testnet,
+		Network::Regtest => bitcoin_bech32::constants::Network::Regtest,
+	}
+}